@@ -0,0 +1,33 @@
+// Renders 1000 identical notes, which the note cache synthesizes only once, and
+// 1000 notes that each differ slightly in pitch, which it has to synthesize one
+// by one. Run with `cargo bench --bench cache`.
+
+mod common;
+
+use std::time::Duration;
+use untz::{Instrument, Note, Song, Track};
+
+const NOTES: usize = 1000;
+const SAMPLE_RATE: u32 = 44100;
+
+fn song(freq: impl Fn(usize) -> f64) -> Song {
+    let mut track = Track::new(Instrument::Saw);
+    for i in 0..NOTES {
+        track.note(Note::new(freq(i), 0.5, i as f64 * 0.1, 0.25));
+    }
+    let mut song = Song::new();
+    song.track(track);
+    song
+}
+
+fn time(song: &Song) -> Duration {
+    common::best_of(|| song.render(SAMPLE_RATE))
+}
+
+fn main() {
+    let identical = time(&song(|_| 440.0));
+    let distinct = time(&song(|i| 440.0 + i as f64 * 0.01));
+    println!("{} identical notes: {:?}", NOTES, identical);
+    println!("{} distinct notes:  {:?}", NOTES, distinct);
+    println!("speedup: {:.1}x", distinct.as_secs_f64() / identical.as_secs_f64());
+}
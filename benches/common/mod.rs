@@ -0,0 +1,17 @@
+// Timing shared by the benches.
+
+use std::time::{Duration, Instant};
+
+const RUNS: u32 = 5;
+
+// Best of `RUNS` calls to `run`, to keep one slow run from skewing the comparison
+pub fn best_of<T>(mut run: impl FnMut() -> T) -> Duration {
+    (0..RUNS)
+        .map(|_| {
+            let now = Instant::now();
+            std::hint::black_box(run());
+            now.elapsed()
+        })
+        .min()
+        .unwrap()
+}
@@ -0,0 +1,16 @@
+// Times sine rendering on whichever path this build uses. Compare
+// `cargo bench --bench sine` against `cargo bench --bench sine --features sine-table`.
+
+mod common;
+
+use untz::{render_note, Instrument, Note};
+
+const SAMPLE_RATE: u32 = 44100;
+const SECONDS: f64 = 60.0;
+
+fn main() {
+    let path = if cfg!(feature = "sine-table") { "table" } else { "exact" };
+    let note = Note::new(440.0, 1.0, 0.0, SECONDS);
+    let best = common::best_of(|| render_note(&Instrument::Sine, &note, SAMPLE_RATE));
+    println!("{} seconds of sine ({}): {:?}", SECONDS, path, best);
+}
@@ -0,0 +1,24 @@
+use untz::{Format, Instrument, Note, SampleFormat, Song, Track, WriteInfo};
+
+fn main() {
+    let mut track1 = Track::new(Instrument::Sine);
+    track1.note(Note::new(440.0, 0.8, 0.0, 1.0));
+    let mut track2 = Track::new(Instrument::Square);
+    track2.note(Note::new(440.0, 0.2, 0.0, 1.0));
+    let mut track3 = Track::new(Instrument::Saw);
+    track3.note(Note::new(440.0, 0.3, 0.0, 1.0));
+
+    let mut song = Song::new();
+    song.track(track1);
+    //song.track(track2);
+    //song.track(track3);
+    song.write(
+        &WriteInfo::builder()
+            .filepath("test.wav")
+            .sample_rate(44100)
+            .channels(1)
+            .format(Format::Wave)
+            .sample_format(SampleFormat::I16)
+            .build()
+    ).expect("Failed to write song");
+}
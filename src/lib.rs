@@ -0,0 +1,4411 @@
+//! Tiny synthesizer and audio file renderer.
+
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+#[cfg(feature = "playback")]
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::f64::consts::PI;
+
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Instrument {
+    Sine,
+    Square,
+    /// Square wave that is high for `duty` of each period. A duty of 0.5 is the
+    /// same as `Square`.
+    Pulse { duty: f64 },
+    Saw,
+    /// Sawtooth with PolyBLEP correction at the reset, which removes most of the
+    /// aliasing the plain `Saw` has at high pitches.
+    BandLimitedSaw,
+    Triangle,
+    /// Noise of the given color. Each note draws from its own generator seeded
+    /// by the note's position in the song, so two identical songs always render
+    /// to byte-identical files.
+    Noise { color: NoiseColor },
+    /// User-defined oscillator, called with the time since the note started and
+    /// the instantaneous frequency. It should return values in [-1.0, 1.0].
+    /// Closures can't be serialized or compared, so songs using this fail to
+    /// serialize and aren't equal to anything.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    Custom(Box<dyn Fn(f64, f64) -> f64 + Send + Sync>),
+    /// Plays back a recording, resampled so that a note at `base_freq` plays it
+    /// at its original speed. Notes longer than the recording either loop it or
+    /// fall silent once it runs out.
+    Sample {
+        data: Vec<f64>,
+        base_freq: f64,
+        sample_rate: u32,
+        looped: bool,
+    },
+    /// Karplus-Strong plucked string: a burst of noise circulating through an
+    /// averaging delay line one period long. `damping` in [0, 1] sets how
+    /// quickly it dies away, from the natural decay at 0.0 to instant at 1.0.
+    Pluck { damping: f64 },
+    /// Two-operator FM: a sine carrier whose phase is modulated by a sine at
+    /// `ratio` times the note's frequency. `index` is the modulation depth and
+    /// can follow an envelope over the note for evolving timbres.
+    Fm {
+        ratio: f64,
+        index: f64,
+        index_envelope: Option<Envelope>,
+    },
+    /// Sum of harmonics, where entry n is the amplitude of harmonic n + 1.
+    /// Harmonics at or above Nyquist are left out rather than aliased.
+    Additive(Vec<f64>),
+    /// A ready-made drum sound that dies away on its own, whatever the note's
+    /// length. The note's frequency tunes the kick and the snare's tone.
+    Drum(DrumSound),
+}
+
+#[derive(PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum DrumSound {
+    /// Sine swept down from three times the note's pitch
+    Kick,
+    /// White noise over a short tone at the note's pitch
+    Snare,
+    /// High-passed noise, short or ringing
+    ClosedHiHat,
+    OpenHiHat,
+}
+
+#[derive(PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum NoiseColor {
+    /// Flat spectrum
+    White,
+    /// -3 dB per octave, via the Voss-McCartney algorithm
+    Pink,
+    /// -6 dB per octave, via a leaky integrator
+    Brown,
+}
+
+// Voss-McCartney generator rows; the lowest row changes every 2 ** 16 samples
+const PINK_ROWS: usize = 16;
+
+// Drum decay time constants in seconds
+const KICK_SWEEP: f64 = 0.02;
+const KICK_DECAY: f64 = 0.15;
+const SNARE_TONE_DECAY: f64 = 0.05;
+const SNARE_NOISE_DECAY: f64 = 0.12;
+const CLOSED_HI_HAT_DECAY: f64 = 0.03;
+const OPEN_HI_HAT_DECAY: f64 = 0.3;
+// Hi-hat noise is high-passed at this frequency
+const HI_HAT_CUTOFF: f64 = 7000.0;
+
+// Per-note oscillator state for instruments that need more than the phase.
+// It lives for the whole note, so instruments that are really rendered as a
+// whole buffer (like Pluck) can keep that buffer here.
+struct Voice {
+    rng: Rng,
+    // Read position into a sample's data, in source samples
+    position: f64,
+    delay_line: Vec<f64>,
+    delay_pos: usize,
+    modulator_phase: f64,
+    noise_rows: Vec<f64>,
+    noise_counter: u32,
+    noise_level: f64,
+    // Time since the note started and its full length, in seconds
+    time: f64,
+    duration: f64,
+    sine: Sine,
+}
+
+impl Voice {
+    fn new(seed: u64, duration: f64) -> Voice {
+        Voice {
+            rng: Rng::new(seed),
+            position: 0.0,
+            delay_line: Vec::new(),
+            delay_pos: 0,
+            modulator_phase: 0.0,
+            noise_rows: Vec::new(),
+            noise_counter: 0,
+            noise_level: 0.0,
+            time: 0.0,
+            duration,
+            sine: Sine::new(),
+        }
+    }
+}
+
+impl Instrument {
+    pub fn custom<F: Fn(f64, f64) -> f64 + Send + Sync + 'static>(oscillator: F) -> Instrument {
+        Instrument::Custom(Box::new(oscillator))
+    }
+
+    // One sample of the waveform at `phase`, measured in cycles in [0.0, 1.0).
+    fn oscillate(&self, phase: f64, freq: f64, sample_rate: u32, voice: &mut Voice) -> f64 {
+        match self {
+            Instrument::Sine => {
+                voice.sine.at(phase)
+            },
+            Instrument::Square => {
+                if phase < 0.5 {1.0} else {-1.0}
+            },
+            Instrument::Pulse { duty } => {
+                if phase < *duty {1.0} else {-1.0}
+            },
+            Instrument::Saw => {
+                2.0 * phase - 1.0
+            },
+            Instrument::BandLimitedSaw => {
+                2.0 * phase - 1.0 - poly_blep(phase, freq / sample_rate as f64)
+            },
+            Instrument::Triangle => {
+                2.0 * (2.0 * phase - 1.0).abs() - 1.0
+            },
+            Instrument::Noise { color } => {
+                let white = 2.0 * voice.rng.next_f64() - 1.0;
+                match color {
+                    NoiseColor::White => white,
+                    NoiseColor::Pink => {
+                        if voice.noise_rows.is_empty() {
+                            voice.noise_rows = (0..PINK_ROWS).map(|_| 2.0 * voice.rng.next_f64() - 1.0).collect();
+                            voice.noise_level = voice.noise_rows.iter().sum();
+                        }
+                        // Each row is refreshed half as often as the one before it
+                        voice.noise_counter = voice.noise_counter.wrapping_add(1);
+                        let row = voice.noise_counter.trailing_zeros() as usize;
+                        if row < PINK_ROWS {
+                            let new = 2.0 * voice.rng.next_f64() - 1.0;
+                            voice.noise_level += new - voice.noise_rows[row];
+                            voice.noise_rows[row] = new;
+                        }
+                        // Scaled to roughly the loudness of white noise
+                        let pink = (voice.noise_level + white) / ((PINK_ROWS + 1) as f64).sqrt();
+                        pink.clamp(-1.0, 1.0)
+                    },
+                    NoiseColor::Brown => {
+                        voice.noise_level = 0.995 * voice.noise_level + 0.05 * white;
+                        voice.noise_level.clamp(-1.0, 1.0)
+                    },
+                }
+            },
+            Instrument::Custom(oscillator) => {
+                oscillator(voice.time, freq)
+            },
+            Instrument::Sample { data, base_freq, sample_rate: data_rate, looped } => {
+                if data.is_empty() {
+                    return 0.0;
+                }
+                if *looped {
+                    voice.position %= data.len() as f64;
+                }
+                let idx = voice.position as usize;
+                let frac = voice.position - idx as f64;
+                voice.position += freq / base_freq * *data_rate as f64 / sample_rate as f64;
+                if idx >= data.len() {
+                    return 0.0;
+                }
+                let next = if idx + 1 < data.len() {
+                    data[idx + 1]
+                } else if *looped {
+                    data[0]
+                } else {
+                    0.0
+                };
+                data[idx] + frac * (next - data[idx])
+            },
+            Instrument::Pluck { damping } => {
+                if voice.delay_line.is_empty() {
+                    // The string's length is fixed by the pitch it was plucked at
+                    let len = ((sample_rate as f64 / freq.abs()).round() as usize).min(sample_rate as usize).max(2);
+                    voice.delay_line = (0..len).map(|_| 2.0 * voice.rng.next_f64() - 1.0).collect();
+                }
+                let len = voice.delay_line.len();
+                let pos = voice.delay_pos;
+                let out = voice.delay_line[pos];
+                let next = voice.delay_line[(pos + 1) % len];
+                voice.delay_line[pos] = (1.0 - damping) * 0.5 * (out + next);
+                voice.delay_pos = (pos + 1) % len;
+                out
+            },
+            Instrument::Fm { ratio, index, index_envelope } => {
+                let mut index = *index;
+                if let Some(envelope) = index_envelope {
+                    index *= envelope.gain(voice.time, voice.duration);
+                }
+                let modulator = voice.sine.at(voice.modulator_phase);
+                voice.modulator_phase = f64::fract(voice.modulator_phase + ratio * freq / sample_rate as f64);
+                voice.sine.at(phase + index * modulator / (2.0 * PI))
+            },
+            Instrument::Additive(amplitudes) => {
+                let sine = voice.sine;
+                let nyquist = sample_rate as f64 / 2.0;
+                amplitudes.iter().enumerate()
+                    .map(|(n, amp)| (n as f64 + 1.0, amp))
+                    .take_while(|(harmonic, _)| harmonic * freq.abs() < nyquist)
+                    .map(|(harmonic, amp)| amp * sine.at(harmonic * phase))
+                    .sum()
+            },
+            Instrument::Drum(sound) => {
+                let t = voice.time;
+                match sound {
+                    DrumSound::Kick => {
+                        // The extra phase from sweeping down, integrated in closed form
+                        let sweep = 2.0 * freq * KICK_SWEEP * (1.0 - f64::exp(-t / KICK_SWEEP));
+                        voice.sine.at(phase + sweep) * f64::exp(-t / KICK_DECAY)
+                    },
+                    DrumSound::Snare => {
+                        let tone = voice.sine.at(phase) * f64::exp(-t / SNARE_TONE_DECAY);
+                        let noise = (2.0 * voice.rng.next_f64() - 1.0) * f64::exp(-t / SNARE_NOISE_DECAY);
+                        0.4 * tone + 0.6 * noise
+                    },
+                    DrumSound::ClosedHiHat | DrumSound::OpenHiHat => {
+                        let decay = if let DrumSound::OpenHiHat = sound {OPEN_HI_HAT_DECAY} else {CLOSED_HI_HAT_DECAY};
+                        // White noise minus a one-pole low-passed copy of itself
+                        let white = 2.0 * voice.rng.next_f64() - 1.0;
+                        let coef = 1.0 - f64::exp(-2.0 * PI * HI_HAT_CUTOFF / sample_rate as f64);
+                        voice.noise_level += coef * (white - voice.noise_level);
+                        ((white - voice.noise_level) * f64::exp(-t / decay)).clamp(-1.0, 1.0)
+                    },
+                }
+            },
+        }
+    }
+
+    // Whether the instrument draws from the note's random generator, in which
+    // case no two notes sound the same.
+    fn is_random(&self) -> bool {
+        match self {
+            Instrument::Noise { .. } | Instrument::Pluck { .. } => true,
+            Instrument::Drum(sound) => !matches!(sound, DrumSound::Kick),
+            _ => false,
+        }
+    }
+
+    fn validate(&self) -> Result<(), UntzError> {
+        match self {
+            Instrument::Pulse { duty } => check_finite("pulse duty", *duty),
+            Instrument::Fm { ratio, index, .. } => {
+                check_finite("FM ratio", *ratio)?;
+                check_finite("FM index", *index)
+            },
+            Instrument::Pluck { damping } => {
+                if !(*damping >= 0.0 && *damping <= 1.0) {
+                    return Err(UntzError::InvalidParameter(format!("pluck damping must be in [0, 1] ({})", damping)));
+                }
+                Ok(())
+            },
+            Instrument::Sample { base_freq, sample_rate, .. } => {
+                if *sample_rate == 0 {
+                    return Err(UntzError::InvalidSampleRate(*sample_rate));
+                }
+                if !(base_freq.is_finite() && *base_freq > 0.0) {
+                    return Err(UntzError::InvalidParameter(format!("sample base_freq must be positive ({})", base_freq)));
+                }
+                Ok(())
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+// Closures can't be compared, so a Custom instrument isn't equal to anything,
+// itself included.
+impl PartialEq for Instrument {
+    fn eq(&self, other: &Instrument) -> bool {
+        match (self, other) {
+            (Instrument::Custom(_), _) | (_, Instrument::Custom(_)) => false,
+            (Instrument::Pulse { duty }, Instrument::Pulse { duty: other_duty }) => duty == other_duty,
+            (Instrument::Noise { color }, Instrument::Noise { color: other_color }) => color == other_color,
+            (
+                Instrument::Sample { data, base_freq, sample_rate, looped },
+                Instrument::Sample { data: other_data, base_freq: other_base_freq, sample_rate: other_sample_rate, looped: other_looped },
+            ) => data == other_data && base_freq == other_base_freq && sample_rate == other_sample_rate && looped == other_looped,
+            (Instrument::Pluck { damping }, Instrument::Pluck { damping: other_damping }) => damping == other_damping,
+            (
+                Instrument::Fm { ratio, index, index_envelope },
+                Instrument::Fm { ratio: other_ratio, index: other_index, index_envelope: other_index_envelope },
+            ) => ratio == other_ratio && index == other_index && index_envelope == other_index_envelope,
+            (Instrument::Additive(harmonics), Instrument::Additive(other_harmonics)) => harmonics == other_harmonics,
+            (Instrument::Drum(sound), Instrument::Drum(other_sound)) => sound == other_sound,
+            // The rest have no fields, so they're equal if they're the same variant
+            _ => std::mem::discriminant(self) == std::mem::discriminant(other),
+        }
+    }
+}
+
+struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        Rng {
+            state: seed,
+        }
+    }
+
+    // splitmix64
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    // Uniform in [0.0, 1.0)
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1_u64 << 53) as f64
+    }
+}
+
+#[derive(Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Envelope {
+    pub attack: f64,
+    pub decay: f64,
+    pub sustain: f64,
+    pub release: f64,
+}
+
+#[derive(Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Note {
+    pub freq: f64,
+    pub volume: f64,
+    pub start: f64,
+    pub duration: f64,
+    pub envelope: Option<Envelope>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub pan: f64,
+    /// Vibrato rate in Hz and depth in cents; a depth of 0.0 disables it
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub vibrato_rate: f64,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub vibrato_depth: f64,
+    /// Starting phase of the oscillator, in cycles (0.5 starts half a period in)
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub phase: f64,
+    /// Pitch offset in cents, for thickening layered notes
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub detune_cents: f64,
+    /// Pitch bend as (fraction of the note's duration, offset in semitones)
+    /// breakpoints in time order, interpolated linearly and held flat before the
+    /// first and after the last. Empty means no bend.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub pitch_envelope: Vec<(f64, f64)>,
+    /// Pan automation as (fraction of the duration, pan) breakpoints, read the
+    /// same way as the pitch envelope. When empty the note stays at `pan`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub pan_envelope: Vec<(f64, f64)>,
+    /// Slides the pitch continuously from `freq` to another frequency over the
+    /// whole note, for sirens and risers
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub sweep: Option<Sweep>,
+    /// Carries on an earlier note in the track at the same frequency that ends
+    /// where this one starts, so the two sound as one note without a second
+    /// attack. The earlier note's settings are kept for the whole length.
+    /// Ignored when there is no such note.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub tie: bool,
+}
+
+/// How a sweeping note gets from its `freq` to `end_freq`. A sweep that ends
+/// where it starts plays at a constant pitch.
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Sweep {
+    /// The same number of Hz every second
+    Linear { end_freq: f64 },
+    /// The same number of semitones every second, which is how a steady slide
+    /// sounds
+    Exponential { end_freq: f64 },
+}
+
+impl Sweep {
+    fn end_freq(&self) -> f64 {
+        match self {
+            Sweep::Linear { end_freq } | Sweep::Exponential { end_freq } => *end_freq,
+        }
+    }
+
+    // The frequency `fraction` of the way through a note starting at `freq`.
+    fn freq(&self, freq: f64, fraction: f64) -> f64 {
+        match self {
+            Sweep::Linear { end_freq } => freq + (end_freq - freq) * fraction,
+            Sweep::Exponential { end_freq } => freq * f64::powf(end_freq / freq, fraction),
+        }
+    }
+}
+
+#[derive(PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Track {
+    /// Identifies the track in stem filenames and error messages. Empty means
+    /// the track goes by its position in the song; see `label`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub name: String,
+    pub notes: Vec<Note>,
+    pub instrument: Instrument,
+    /// When set, note starts and durations are given in beats at this tempo
+    /// instead of seconds.
+    pub bpm: Option<f64>,
+    #[cfg_attr(feature = "serde", serde(default = "unity"))]
+    pub gain: f64,
+    /// How each note's volume maps to amplitude
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub velocity_curve: VelocityCurve,
+    /// Seconds each note takes to slide from the previous note's pitch to its own
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub glide: f64,
+    /// Tremolo rate in Hz and depth in [0, 1], timed from the start of the song so
+    /// every note in the track pulses together. A depth of 0.0 disables it.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub tremolo_rate: f64,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub tremolo_depth: f64,
+    /// Applied in order to the track's mix, before it is summed with other tracks
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub effects: Vec<Effect>,
+    /// Filters each note on its own before it's mixed, so the cutoff can follow
+    /// the note
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub filter: Option<NoteFilter>,
+    /// Delays notes on the off-beat eighth of each beat, from straight at 0.0 to
+    /// triplet swing at 0.5. Only applies to tracks timed in beats.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub swing: f64,
+    /// Fraction of each note's written duration that actually sounds: 0.5 for
+    /// staccato, above 1.0 to run into the next note for legato. Envelopes fit
+    /// their release into the sounding part.
+    #[cfg_attr(feature = "serde", serde(default = "unity"))]
+    pub articulation: f64,
+    /// Where notes overlap, fades the earlier one out while the later one fades
+    /// in (at equal power) rather than summing them, for smooth monophonic
+    /// lines. Leave it off for chords and anything else meant to overlap.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub crossfade: bool,
+    /// Gain over time as (time, gain) breakpoints in the track's units, read
+    /// like a note's pitch envelope but across the whole song. Repeated steps
+    /// make a trance gate or a sidechain-style pump. Empty means no automation.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub volume_automation: Vec<(f64, f64)>,
+    /// A muted track is left out of the mix. If any track is soloed, only soloed
+    /// tracks are heard, whether or not they are also muted.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub muted: bool,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub solo: bool,
+}
+
+/// Curves from a note's volume to its amplitude. Each maps 0.0 to silence and
+/// 1.0 to full volume, and a negative volume to the same curve inverted.
+#[derive(Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum VelocityCurve {
+    /// The volume is the amplitude
+    #[default]
+    Linear,
+    /// (e^(curvature * v) - 1) / (e^curvature - 1), which spends more of the
+    /// range on quiet notes the larger `curvature` is. Near 0.0 it's linear.
+    Exponential { curvature: f64 },
+    /// Each step down from 1.0 takes off the same number of decibels, reaching
+    /// `range` dB down just above 0.0. A range of 12 makes 0.5 sound at -6 dB.
+    Decibels { range: f64 },
+}
+
+impl VelocityCurve {
+    fn amplitude(&self, volume: f64) -> f64 {
+        let v = volume.abs();
+        let amplitude = match self {
+            VelocityCurve::Linear => v,
+            VelocityCurve::Exponential { curvature } => {
+                if curvature.abs() < 1e-9 {
+                    v
+                } else {
+                    f64::exp_m1(curvature * v) / f64::exp_m1(*curvature)
+                }
+            },
+            VelocityCurve::Decibels { range } => {
+                if v == 0.0 {
+                    0.0
+                } else {
+                    f64::powf(10.0, (v - 1.0) * range / 20.0)
+                }
+            },
+        };
+        amplitude.copysign(volume)
+    }
+}
+
+#[derive(PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Effect {
+    /// Shorthand for a `Filter` with `FilterType::LowPass`, which it's treated as
+    LowPass { cutoff: f64, q: f64 },
+    /// Any of the biquad filters, with `q` setting the resonance, or the width
+    /// for band-pass, notch and peak filters
+    Filter { filter: FilterType, cutoff: f64, q: f64 },
+    /// Echoes are cut off at the end of the song, so give the song a tail if
+    /// they should ring out.
+    Delay { time: f64, feedback: f64, mix: f64 },
+    /// Quantizes to `bit_depth` bits and holds every `downsample`th sample. The
+    /// hold is counted at the output rate, so it sounds the same however much
+    /// the song is oversampled.
+    Bitcrush { bit_depth: u32, downsample: usize },
+    /// Multiplies by a sine carrier, leaving only sum and difference frequencies
+    RingMod { freq: f64 },
+    /// Schroeder reverb: four parallel combs into two series allpasses.
+    /// `room_size` in [0, 1] sets how long the reverb rings; give the song a
+    /// tail if it should be heard after the last note.
+    Reverb { room_size: f64, mix: f64 },
+    /// Turns down anything above `threshold` (in dBFS) so it only rises 1/`ratio`
+    /// dB per dB. `attack` and `release` are the envelope's time constants in
+    /// seconds. An infinite ratio with a zero attack is a brick-wall limiter.
+    /// Channels share one gain so the stereo image doesn't shift.
+    Compressor { threshold: f64, ratio: f64, attack: f64, release: f64 },
+    /// Mixes in a copy delayed by a sine LFO at `rate` Hz sweeping `depth`
+    /// seconds above a short base delay. Each channel's LFO is a quarter cycle
+    /// ahead of the last, which is what widens a stereo mix.
+    Chorus { rate: f64, depth: f64, mix: f64 },
+    /// Like a chorus but with a much shorter delay, sweeping `depth` seconds
+    /// above 1 ms, and with `feedback` in (-1, 1) to make the moving comb-filter
+    /// notches ring.
+    Flanger { rate: f64, depth: f64, feedback: f64, mix: f64 },
+    /// Multiplies by `drive` and then bends the result through `curve`
+    Waveshaper { curve: ShaperCurve, drive: f64 },
+    /// Plays the buffer backwards. On a track that buffer spans the whole song,
+    /// so the track's sound ends up mirrored around the song's length. That
+    /// needs the whole song at once, so it can't be rendered in chunks.
+    Reverse,
+    /// Mid-side stereo width: scales the difference between the two channels
+    /// by `width`, so 1.0 leaves the mix alone, 0.0 folds it to mono and
+    /// anything above 1.0 spreads it wider. Does nothing unless there are
+    /// exactly two channels.
+    Width { width: f64 },
+}
+
+/// Biquad responses from the RBJ audio EQ cookbook, centered on the cutoff.
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum FilterType {
+    LowPass,
+    HighPass,
+    /// Unity gain at the cutoff, falling away on both sides
+    BandPass,
+    /// Silences the cutoff and leaves the rest
+    Notch,
+    /// Boosts or cuts by `gain` dB around the cutoff
+    Peak { gain: f64 },
+}
+
+/// A track's per-note filter. Over each note the cutoff moves from `cutoff`
+/// toward `peak_cutoff` as `envelope` rises, evenly in pitch, and settles back
+/// as it falls; with no envelope it stays at `cutoff`.
+#[derive(PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct NoteFilter {
+    pub filter: FilterType,
+    pub cutoff: f64,
+    pub peak_cutoff: f64,
+    pub q: f64,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub envelope: Option<Envelope>,
+}
+
+// Samples between recomputing a swept filter's coefficients
+const FILTER_SWEEP_STEP: usize = 16;
+
+impl NoteFilter {
+    fn apply(&self, samples: &mut [f64], duration: f64, sample_rate: u32) {
+        // Kept under Nyquist, which a sweep can otherwise overshoot when rendering at a low rate
+        let max_cutoff = 0.49 * sample_rate as f64;
+        let cutoff_at = |i: usize| {
+            let amount = self.envelope.as_ref().map_or(0.0, |envelope| envelope.gain(i as f64 / sample_rate as f64, duration));
+            (self.cutoff * f64::powf(self.peak_cutoff / self.cutoff, amount)).min(max_cutoff)
+        };
+        let mut biquad = Biquad::new(self.filter, cutoff_at(0), self.q, sample_rate);
+        for (i, sample) in samples.iter_mut().enumerate() {
+            if i > 0 && self.envelope.is_some() && i.is_multiple_of(FILTER_SWEEP_STEP) {
+                biquad.retune(self.filter, cutoff_at(i), self.q, sample_rate);
+            }
+            *sample = biquad.process(*sample);
+        }
+    }
+
+    fn validate(&self, what: &str) -> Result<(), UntzError> {
+        for (field, value) in [("cutoff", self.cutoff), ("peak_cutoff", self.peak_cutoff), ("Q", self.q)].iter() {
+            if !(value.is_finite() && *value > 0.0) {
+                return Err(UntzError::InvalidParameter(format!("{} filter {} must be positive ({})", what, field, value)));
+            }
+        }
+        if let FilterType::Peak { gain } = self.filter {
+            check_finite(&format!("{} peak filter gain", what), gain)?;
+        }
+        if let Some(envelope) = &self.envelope {
+            for (field, value) in [
+                ("attack", envelope.attack),
+                ("decay", envelope.decay),
+                ("sustain", envelope.sustain),
+                ("release", envelope.release),
+            ].iter() {
+                check_finite(&format!("{} filter envelope {}", what, field), *value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Transfer functions for `Effect::Waveshaper`. All of them map [-1, 1] into
+/// itself except where noted.
+#[derive(PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum ShaperCurve {
+    HardClip,
+    Tanh,
+    /// Scaled so it approaches but never reaches full scale
+    Arctan,
+    /// x - x^3 / 3, flat beyond full scale, scaled up to reach it
+    Cubic,
+    /// Reflects off full scale instead of clamping, so more drive keeps folding
+    /// the wave back on itself
+    Foldback,
+}
+
+impl ShaperCurve {
+    fn shape(&self, x: f64) -> f64 {
+        match self {
+            ShaperCurve::HardClip => x.clamp(-1.0, 1.0),
+            ShaperCurve::Tanh => x.tanh(),
+            ShaperCurve::Arctan => 2.0 / PI * x.atan(),
+            ShaperCurve::Cubic => {
+                let x = x.clamp(-1.0, 1.0);
+                1.5 * (x - x * x * x / 3.0)
+            },
+            ShaperCurve::Foldback => ((x - 1.0).rem_euclid(4.0) - 2.0).abs() - 1.0,
+        }
+    }
+}
+
+const CHORUS_BASE_DELAY: f64 = 0.015;
+const FLANGER_BASE_DELAY: f64 = 0.001;
+
+// Schroeder's comb delays, chosen to be mutually prime-ish so the echoes don't
+// pile up on each other
+const REVERB_COMB_DELAYS: [f64; 4] = [0.0297, 0.0371, 0.0411, 0.0437];
+const REVERB_ALLPASS_DELAYS: [f64; 2] = [0.005, 0.0017];
+const REVERB_ALLPASS_GAIN: f64 = 0.7;
+
+/// Feedback comb filter, `y[n] = x[n - delay] + feedback * y[n - delay]`, with the
+/// delay in samples. Its impulse response is a train of echoes every `delay`
+/// samples, each `feedback` times the last. Keep |feedback| below 1.0 or it
+/// never dies away.
+pub struct CombFilter {
+    buffer: Vec<f64>,
+    pos: usize,
+    feedback: f64,
+}
+
+impl CombFilter {
+    /// Delays shorter than one sample are treated as one sample.
+    pub fn new(delay: usize, feedback: f64) -> CombFilter {
+        CombFilter {
+            buffer: vec![0_f64; delay.max(1)],
+            pos: 0,
+            feedback,
+        }
+    }
+
+    pub fn delay(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Can be changed while running without clearing the delay line.
+    pub fn set_feedback(&mut self, feedback: f64) {
+        self.feedback = feedback;
+    }
+
+    pub fn process(&mut self, x: f64) -> f64 {
+        let y = self.buffer[self.pos];
+        self.buffer[self.pos] = x + self.feedback * y;
+        self.pos = (self.pos + 1) % self.buffer.len();
+        y
+    }
+}
+
+/// Schroeder allpass, `y[n] = -gain * x[n] + x[n - delay] + gain * y[n - delay]`.
+/// It passes every frequency at the same level but smears the signal in time:
+/// an impulse comes out as -gain, then (1 - gain^2) times successive powers of
+/// `gain` every `delay` samples.
+pub struct AllpassFilter {
+    buffer: Vec<f64>,
+    pos: usize,
+    gain: f64,
+}
+
+impl AllpassFilter {
+    /// Delays shorter than one sample are treated as one sample.
+    pub fn new(delay: usize, gain: f64) -> AllpassFilter {
+        AllpassFilter {
+            buffer: vec![0_f64; delay.max(1)],
+            pos: 0,
+            gain,
+        }
+    }
+
+    pub fn delay(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Can be changed while running without clearing the delay line.
+    pub fn set_gain(&mut self, gain: f64) {
+        self.gain = gain;
+    }
+
+    pub fn process(&mut self, x: f64) -> f64 {
+        let delayed = self.buffer[self.pos];
+        let y = -self.gain * x + delayed;
+        self.buffer[self.pos] = x + self.gain * y;
+        self.pos = (self.pos + 1) % self.buffer.len();
+        y
+    }
+}
+
+// Direct form I biquad using the RBJ audio EQ cookbook coefficients.
+struct Biquad {
+    b0: f64,
+    b1: f64,
+    b2: f64,
+    a1: f64,
+    a2: f64,
+    x1: f64,
+    x2: f64,
+    y1: f64,
+    y2: f64,
+}
+
+impl Biquad {
+    fn new(filter: FilterType, cutoff: f64, q: f64, sample_rate: u32) -> Biquad {
+        let w0 = 2.0 * PI * cutoff / sample_rate as f64;
+        let cos_w0 = f64::cos(w0);
+        let alpha = f64::sin(w0) / (2.0 * q);
+        let (b0, b1, b2, a0, a1, a2) = match filter {
+            FilterType::LowPass => ((1.0 - cos_w0) / 2.0, 1.0 - cos_w0, (1.0 - cos_w0) / 2.0, 1.0 + alpha, -2.0 * cos_w0, 1.0 - alpha),
+            FilterType::HighPass => ((1.0 + cos_w0) / 2.0, -(1.0 + cos_w0), (1.0 + cos_w0) / 2.0, 1.0 + alpha, -2.0 * cos_w0, 1.0 - alpha),
+            FilterType::BandPass => (alpha, 0.0, -alpha, 1.0 + alpha, -2.0 * cos_w0, 1.0 - alpha),
+            FilterType::Notch => (1.0, -2.0 * cos_w0, 1.0, 1.0 + alpha, -2.0 * cos_w0, 1.0 - alpha),
+            FilterType::Peak { gain } => {
+                let a = f64::powf(10.0, gain / 40.0);
+                (1.0 + alpha * a, -2.0 * cos_w0, 1.0 - alpha * a, 1.0 + alpha / a, -2.0 * cos_w0, 1.0 - alpha / a)
+            },
+        };
+        Biquad {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+            x1: 0.0,
+            x2: 0.0,
+            y1: 0.0,
+            y2: 0.0,
+        }
+    }
+
+    // Changes the response without clearing the filter's memory, for sweeping it.
+    fn retune(&mut self, filter: FilterType, cutoff: f64, q: f64, sample_rate: u32) {
+        let tuned = Biquad::new(filter, cutoff, q, sample_rate);
+        self.b0 = tuned.b0;
+        self.b1 = tuned.b1;
+        self.b2 = tuned.b2;
+        self.a1 = tuned.a1;
+        self.a2 = tuned.a2;
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b0 * x + self.b1 * self.x1 + self.b2 * self.x2 - self.a1 * self.y1 - self.a2 * self.y2;
+        self.x2 = self.x1;
+        self.x1 = x;
+        self.y2 = self.y1;
+        self.y1 = y;
+        y
+    }
+}
+
+// The most recent samples through a modulated delay line, indexed from the
+// start of the render. Only the last `len` are kept; anything older, or not
+// written yet, reads as silence.
+struct History {
+    samples: VecDeque<f64>,
+    first: usize,
+    len: usize,
+}
+
+impl History {
+    fn new(max_delay: f64, sample_rate: u32) -> History {
+        // Interpolated reads reach one sample further back than the delay
+        let len = (max_delay * sample_rate as f64).ceil() as usize + 2;
+        History { samples: VecDeque::with_capacity(len + 1), first: 0, len }
+    }
+
+    fn get(&self, idx: usize) -> f64 {
+        idx.checked_sub(self.first).and_then(|i| self.samples.get(i)).copied().unwrap_or(0.0)
+    }
+
+    fn push(&mut self, sample: f64) {
+        self.samples.push_back(sample);
+        if self.samples.len() > self.len {
+            self.samples.pop_front();
+            self.first += 1;
+        }
+    }
+}
+
+// Whatever an effect has to remember from one block of samples to the next,
+// so rendering in blocks sounds the same as rendering everything at once.
+enum EffectState {
+    Stateless,
+    Filters(Vec<Biquad>),
+    DelayLines(Vec<Vec<f64>>),
+    // The value each channel is holding, and how many samples at the rendering
+    // rate each held value lasts
+    Held { values: Vec<f64>, hold: f64 },
+    Reverb(Vec<(Vec<CombFilter>, Vec<AllpassFilter>)>),
+    Histories(Vec<History>),
+    Level(f64),
+}
+
+impl Effect {
+    // `output_rate` is the rate the song will end up at, which differs from
+    // `sample_rate` when oversampling or rendering at another rate.
+    fn state(&self, num_channels: usize, sample_rate: u32, output_rate: u32) -> EffectState {
+        match self {
+            Effect::LowPass { cutoff, q } => Effect::low_pass_filter(*cutoff, *q).state(num_channels, sample_rate, output_rate),
+            Effect::Filter { filter, cutoff, q } => {
+                EffectState::Filters((0..num_channels).map(|_| Biquad::new(*filter, *cutoff, *q, sample_rate)).collect())
+            },
+            Effect::Delay { time, .. } => {
+                let delay_len = ((time * sample_rate as f64).round() as usize).max(1);
+                EffectState::DelayLines(vec![vec![0_f64; delay_len]; num_channels])
+            },
+            Effect::Bitcrush { downsample, .. } => EffectState::Held {
+                values: vec![0_f64; num_channels],
+                hold: *downsample as f64 * sample_rate as f64 / output_rate as f64,
+            },
+            Effect::Reverb { room_size, .. } => {
+                let feedback = 0.7 + 0.28 * room_size;
+                let samples = |seconds: f64| (seconds * sample_rate as f64).round() as usize;
+                EffectState::Reverb((0..num_channels).map(|_| {
+                    let combs = REVERB_COMB_DELAYS.iter()
+                        .map(|&delay| CombFilter::new(samples(delay), feedback))
+                        .collect();
+                    let allpasses = REVERB_ALLPASS_DELAYS.iter()
+                        .map(|&delay| AllpassFilter::new(samples(delay), REVERB_ALLPASS_GAIN))
+                        .collect();
+                    (combs, allpasses)
+                }).collect())
+            },
+            Effect::Chorus { depth, .. } => {
+                EffectState::Histories((0..num_channels).map(|_| History::new(CHORUS_BASE_DELAY + depth, sample_rate)).collect())
+            },
+            Effect::Flanger { depth, .. } => {
+                EffectState::Histories((0..num_channels).map(|_| History::new(FLANGER_BASE_DELAY + depth, sample_rate)).collect())
+            },
+            Effect::Compressor { .. } => EffectState::Level(0.0),
+            Effect::RingMod { .. } | Effect::Waveshaper { .. } | Effect::Reverse | Effect::Width { .. } => EffectState::Stateless,
+        }
+    }
+
+    // Processes the block of samples starting `first_sample` into the render,
+    // carrying `state` over from the block before it. A whole render is just
+    // one block starting at 0 with a fresh state.
+    fn apply_block(&self, state: &mut EffectState, channels: &mut [Vec<f64>], sample_rate: u32, first_sample: usize) {
+        match (self, state) {
+            (Effect::LowPass { .. } | Effect::Filter { .. }, EffectState::Filters(filters)) => {
+                for (channel, filter) in channels.iter_mut().zip(filters.iter_mut()) {
+                    for sample in channel.iter_mut() {
+                        *sample = filter.process(*sample);
+                    }
+                }
+            },
+            (Effect::Delay { feedback, mix, .. }, EffectState::DelayLines(delay_lines)) => {
+                for (channel, delay_line) in channels.iter_mut().zip(delay_lines.iter_mut()) {
+                    let delay_len = delay_line.len();
+                    for (i, sample) in channel.iter_mut().enumerate() {
+                        let pos = (first_sample + i) % delay_len;
+                        let delayed = delay_line[pos];
+                        delay_line[pos] = *sample + feedback * delayed;
+                        *sample = (1.0 - mix) * *sample + mix * delayed;
+                    }
+                }
+            },
+            (Effect::Bitcrush { bit_depth, .. }, EffectState::Held { values, hold }) => {
+                let levels = f64::powi(2.0, *bit_depth as i32 - 1);
+                for (channel, held) in channels.iter_mut().zip(values.iter_mut()) {
+                    for (i, sample) in channel.iter_mut().enumerate() {
+                        // A new value is taken each time the position passes a
+                        // multiple of the hold, which needn't be a whole number
+                        let pos = first_sample + i;
+                        if pos == 0 || (pos as f64 / *hold).floor() != ((pos - 1) as f64 / *hold).floor() {
+                            // Clamped so `bit_depth` bits give exactly 2^bit_depth steps
+                            *held = (*sample * levels).round().clamp(-levels, levels - 1.0) / levels;
+                        }
+                        *sample = *held;
+                    }
+                }
+            },
+            (Effect::RingMod { freq }, _) => {
+                let sine = Sine::new();
+                for channel in channels.iter_mut() {
+                    for (i, sample) in channel.iter_mut().enumerate() {
+                        *sample *= sine.at(freq * (first_sample + i) as f64 / sample_rate as f64);
+                    }
+                }
+            },
+            (Effect::Reverb { mix, .. }, EffectState::Reverb(filters)) => {
+                for (channel, (combs, allpasses)) in channels.iter_mut().zip(filters.iter_mut()) {
+                    for sample in channel.iter_mut() {
+                        let mut wet = combs.iter_mut().map(|comb| comb.process(*sample)).sum::<f64>() / combs.len() as f64;
+                        for allpass in allpasses.iter_mut() {
+                            wet = allpass.process(wet);
+                        }
+                        *sample = (1.0 - mix) * *sample + mix * wet;
+                    }
+                }
+            },
+            (Effect::Chorus { rate, depth, mix }, EffectState::Histories(histories)) => {
+                let sine = Sine::new();
+                for (channel_idx, (channel, dry)) in channels.iter_mut().zip(histories.iter_mut()).enumerate() {
+                    let offset = 0.25 * channel_idx as f64;
+                    for (i, sample) in channel.iter_mut().enumerate() {
+                        let i = first_sample + i;
+                        dry.push(*sample);
+                        let t = i as f64 / sample_rate as f64;
+                        let lfo = 0.5 + 0.5 * sine.at(rate * t + offset);
+                        // Linearly interpolated read, silent before the start
+                        let position = i as f64 - (CHORUS_BASE_DELAY + depth * lfo) * sample_rate as f64;
+                        let wet = if position < 0.0 {
+                            0.0
+                        } else {
+                            let idx = position as usize;
+                            let frac = position - idx as f64;
+                            dry.get(idx) + frac * (dry.get(idx + 1) - dry.get(idx))
+                        };
+                        *sample = (1.0 - mix) * *sample + mix * wet;
+                    }
+                }
+            },
+            (Effect::Flanger { rate, depth, feedback, mix }, EffectState::Histories(lines)) => {
+                let sine = Sine::new();
+                // Each line holds what was written into the delay, feedback included
+                for (channel_idx, (channel, line)) in channels.iter_mut().zip(lines.iter_mut()).enumerate() {
+                    let offset = 0.25 * channel_idx as f64;
+                    for (i, sample) in channel.iter_mut().enumerate() {
+                        let i = first_sample + i;
+                        let t = i as f64 / sample_rate as f64;
+                        let lfo = 0.5 + 0.5 * sine.at(rate * t + offset);
+                        let position = i as f64 - (FLANGER_BASE_DELAY + depth * lfo) * sample_rate as f64;
+                        let delayed = if position < 0.0 {
+                            0.0
+                        } else {
+                            let idx = position as usize;
+                            let frac = position - idx as f64;
+                            let current = line.get(idx);
+                            current + frac * (line.get(idx + 1) - current)
+                        };
+                        line.push(*sample + feedback * delayed);
+                        *sample = (1.0 - mix) * *sample + mix * delayed;
+                    }
+                }
+            },
+            (Effect::Waveshaper { curve, drive }, _) => {
+                for sample in channels.iter_mut().flatten() {
+                    *sample = curve.shape(drive * *sample);
+                }
+            },
+            (Effect::Reverse, _) => {
+                for channel in channels.iter_mut() {
+                    channel.reverse();
+                }
+            },
+            (Effect::Width { width }, _) => {
+                // Splitting and rejoining can round the last bit, so skip it when
+                // there's nothing to change
+                if *width == 1.0 {
+                    return;
+                }
+                if let [left, right] = channels {
+                    for (l, r) in left.iter_mut().zip(right.iter_mut()) {
+                        let mid = 0.5 * (*l + *r);
+                        let side = 0.5 * (*l - *r) * width;
+                        *l = mid + side;
+                        *r = mid - side;
+                    }
+                }
+            },
+            (Effect::Compressor { threshold, ratio, attack, release }, EffectState::Level(level)) => {
+                let coefficient = |time: f64| f64::exp(-1.0 / (time * sample_rate as f64));
+                let (attack_coef, release_coef) = (coefficient(*attack), coefficient(*release));
+                let num_samples = channels.first().map_or(0, |channel| channel.len());
+                for i in 0..num_samples {
+                    let peak = channels.iter().fold(0_f64, |peak, channel| peak.max(channel[i].abs()));
+                    let coef = if peak > *level {attack_coef} else {release_coef};
+                    *level = coef * *level + (1.0 - coef) * peak;
+                    let over = 20.0 * level.log10() - threshold;
+                    if over > 0.0 {
+                        let gain = f64::powf(10.0, -over * (1.0 - 1.0 / ratio) / 20.0);
+                        for channel in channels.iter_mut() {
+                            channel[i] *= gain;
+                        }
+                    }
+                }
+            },
+            _ => unreachable!("effect state doesn't match its effect"),
+        }
+    }
+
+    fn low_pass_filter(cutoff: f64, q: f64) -> Effect {
+        Effect::Filter { filter: FilterType::LowPass, cutoff, q }
+    }
+
+    fn validate(&self, sample_rate: u32) -> Result<(), UntzError> {
+        match self {
+            Effect::LowPass { cutoff, q } => Effect::low_pass_filter(*cutoff, *q).validate(sample_rate)?,
+            Effect::Filter { filter, cutoff, q } => {
+                if !(*cutoff > 0.0 && *cutoff < sample_rate as f64 / 2.0) {
+                    return Err(UntzError::InvalidParameter(format!("filter cutoff must be between 0 and Nyquist ({})", cutoff)));
+                }
+                if !(q.is_finite() && *q > 0.0) {
+                    return Err(UntzError::InvalidParameter(format!("filter Q must be positive ({})", q)));
+                }
+                if let FilterType::Peak { gain } = filter {
+                    check_finite("peak filter gain", *gain)?;
+                }
+            },
+            Effect::Delay { time, feedback, mix } => {
+                if !(time.is_finite() && *time > 0.0) {
+                    return Err(UntzError::InvalidParameter(format!("delay time must be positive ({})", time)));
+                }
+                // A feedback of 1.0 or more never decays
+                if !(*feedback >= 0.0 && *feedback < 1.0) {
+                    return Err(UntzError::InvalidParameter(format!("delay feedback must be in [0, 1) ({})", feedback)));
+                }
+                if !(*mix >= 0.0 && *mix <= 1.0) {
+                    return Err(UntzError::InvalidParameter(format!("delay mix must be in [0, 1] ({})", mix)));
+                }
+            },
+            Effect::Bitcrush { bit_depth, downsample } => {
+                if *bit_depth == 0 || *bit_depth > 32 {
+                    return Err(UntzError::InvalidParameter(format!("bitcrush bit_depth must be in [1, 32] ({})", bit_depth)));
+                }
+                if *downsample == 0 {
+                    return Err(UntzError::InvalidParameter(String::from("bitcrush downsample must be at least 1")));
+                }
+            },
+            Effect::RingMod { freq } => {
+                check_finite("ring mod freq", *freq)?;
+            },
+            Effect::Reverb { room_size, mix } => {
+                if !(*room_size >= 0.0 && *room_size <= 1.0) {
+                    return Err(UntzError::InvalidParameter(format!("reverb room_size must be in [0, 1] ({})", room_size)));
+                }
+                if !(*mix >= 0.0 && *mix <= 1.0) {
+                    return Err(UntzError::InvalidParameter(format!("reverb mix must be in [0, 1] ({})", mix)));
+                }
+            },
+            Effect::Chorus { rate, depth, mix } => {
+                for (what, value) in [("rate", rate), ("depth", depth)].iter() {
+                    if !(value.is_finite() && **value >= 0.0) {
+                        return Err(UntzError::InvalidParameter(format!("chorus {} must be non-negative ({})", what, value)));
+                    }
+                }
+                if !(*mix >= 0.0 && *mix <= 1.0) {
+                    return Err(UntzError::InvalidParameter(format!("chorus mix must be in [0, 1] ({})", mix)));
+                }
+            },
+            Effect::Flanger { rate, depth, feedback, mix } => {
+                for (what, value) in [("rate", rate), ("depth", depth)].iter() {
+                    if !(value.is_finite() && **value >= 0.0) {
+                        return Err(UntzError::InvalidParameter(format!("flanger {} must be non-negative ({})", what, value)));
+                    }
+                }
+                if !(*feedback > -1.0 && *feedback < 1.0) {
+                    return Err(UntzError::InvalidParameter(format!("flanger feedback must be in (-1, 1) ({})", feedback)));
+                }
+                if !(*mix >= 0.0 && *mix <= 1.0) {
+                    return Err(UntzError::InvalidParameter(format!("flanger mix must be in [0, 1] ({})", mix)));
+                }
+            },
+            Effect::Waveshaper { drive, .. } => {
+                check_finite("waveshaper drive", *drive)?;
+            },
+            Effect::Reverse => {},
+            Effect::Width { width } => {
+                if !(width.is_finite() && *width >= 0.0) {
+                    return Err(UntzError::InvalidParameter(format!("stereo width must be non-negative ({})", width)));
+                }
+            },
+            Effect::Compressor { threshold, ratio, attack, release } => {
+                check_finite("compressor threshold", *threshold)?;
+                // Infinity is allowed and means limiting
+                if ratio.is_nan() || *ratio < 1.0 {
+                    return Err(UntzError::InvalidParameter(format!("compressor ratio must be at least 1 ({})", ratio)));
+                }
+                for (what, value) in [("attack", attack), ("release", release)].iter() {
+                    if !(value.is_finite() && **value >= 0.0) {
+                        return Err(UntzError::InvalidParameter(format!("compressor {} must be non-negative ({})", what, value)));
+                    }
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+#[derive(PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Song {
+    pub tracks: Vec<Track>,
+    #[cfg_attr(feature = "serde", serde(default = "unity"))]
+    pub master: f64,
+    /// Applied in order to the full mix, before the master volume
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub effects: Vec<Effect>,
+    /// Seconds of extra silence after the last note, for effects to ring out
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub tail: f64,
+    /// Linear fades over the start and end of the whole song, in seconds. They
+    /// are shortened to the song's length if they would run past it.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub fade_in: f64,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub fade_out: f64,
+}
+
+#[derive(PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Format {
+    Wave,
+    /// Big-endian FORM/COMM/SSND. Plain AIFF has no float samples and metadata is
+    /// only written to WAV files.
+    Aiff,
+    /// Headerless interleaved little-endian samples in the chosen sample format
+    RawPcm,
+}
+
+#[derive(Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum SampleFormat {
+    #[default]
+    I16,
+    I24,
+    F32,
+}
+
+#[derive(Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Clipping {
+    #[default]
+    Hard,
+    /// tanh saturation, which rounds off overloads instead of flattening them.
+    /// Like all clipping it happens at the output rate, after oversampling, so
+    /// it can alias; for oversampled saturation put a tanh `Effect::Waveshaper`
+    /// on the song instead.
+    Soft,
+}
+
+/// How `resample` interpolates between the original samples.
+#[derive(Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Resampling {
+    /// Straight lines between neighbouring samples. Fast, but it dulls the
+    /// highs and lets some of what's above the new Nyquist alias.
+    Linear,
+    /// Windowed sinc, band-limited to whichever of the two rates is lower
+    #[default]
+    Sinc,
+}
+
+/// How panned notes are folded into mono output. A centered note comes out the
+/// same either way; the choice is how much quieter a hard-panned one is.
+#[derive(Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Downmix {
+    /// Pan has no effect on mono output
+    #[default]
+    Ignore,
+    /// Sum of the equal-power stereo channels, scaled so hard-panned notes are
+    /// 3 dB down
+    Minus3Db,
+    /// Linear crossfade, so hard-panned notes are 6 dB down
+    Minus6Db,
+}
+
+// How rendered notes are spread across the output channels.
+#[derive(Clone, Copy)]
+struct Layout {
+    channels: u16,
+    downmix: Downmix,
+}
+
+pub struct WriteInfo {
+    pub filepath: String,
+    pub sample_rate: u32,
+    /// Number of interleaved output channels. Notes are panned across them in
+    /// order, so 1 is mono and 2 is left/right stereo.
+    pub channels: u16,
+    /// Only used for mono output
+    pub downmix: Downmix,
+    pub format: Format,
+    pub sample_format: SampleFormat,
+    /// Target peak; a louder mix is scaled down to it instead of being clipped.
+    pub normalize: Option<f64>,
+    /// Removes any DC offset from the mix with a gentle high-pass before it's quantized.
+    pub dc_block: bool,
+    /// Adds TPDF dither before integer quantization, trading distortion on quiet
+    /// passages for a little flat noise. Has no effect on F32 output.
+    pub dither: bool,
+    /// Renders at this multiple of the sample rate and filters back down, which
+    /// keeps harsh oscillators and nonlinear effects from aliasing. 1 disables
+    /// it. Clipping, including `Clipping::Soft`, and dither still happen at the
+    /// output rate and aren't covered.
+    pub oversample: u32,
+    /// Mixes the song at this rate instead and resamples it to `sample_rate` for
+    /// the file with `resampling`. Oversampling then multiplies this rate. The
+    /// whole song has to be resampled at once, so it can't be chunked.
+    pub render_rate: Option<u32>,
+    pub resampling: Resampling,
+    /// Renders and writes the song this many frames at a time instead of all at
+    /// once, so memory use stays flat however long the song is. The file comes
+    /// out the same either way, but normalizing takes an extra rendering pass to
+    /// find the peak first.
+    pub chunk_size: Option<u32>,
+    /// First and last frame of a sustain loop, both inclusive, written to a smpl
+    /// chunk for samplers to pick up. Only WAV files have one.
+    pub loop_points: Option<(u32, u32)>,
+    pub clipping: Clipping,
+    pub metadata: Metadata,
+}
+
+/// Tags written to a LIST/INFO chunk. With every field unset no chunk is written.
+#[derive(Default)]
+pub struct Metadata {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub date: Option<String>,
+    pub genre: Option<String>,
+    pub comment: Option<String>,
+}
+
+impl Metadata {
+    // The complete LIST chunk, header included, or nothing if there are no tags.
+    fn info_chunk(&self) -> Vec<u8> {
+        let tags = [
+            (b"INAM", &self.title),
+            (b"IART", &self.artist),
+            (b"IPRD", &self.album),
+            (b"ICRD", &self.date),
+            (b"IGNR", &self.genre),
+            (b"ICMT", &self.comment),
+        ];
+        let mut info = Vec::new();
+        for (id, value) in tags.iter() {
+            if let Some(value) = value {
+                // Null-terminated, and padded so every subchunk starts on an even byte
+                let text_size = value.len() as u32 + 1;
+                info.extend_from_slice(*id);
+                info.extend_from_slice(&text_size.to_le_bytes());
+                info.extend_from_slice(value.as_bytes());
+                info.push(0);
+                if !text_size.is_multiple_of(2) {
+                    info.push(0);
+                }
+            }
+        }
+        if info.is_empty() {
+            return info;
+        }
+        let mut chunk = Vec::with_capacity(info.len() + 12);
+        chunk.extend_from_slice(b"LIST");
+        chunk.extend_from_slice(&(info.len() as u32 + 4).to_le_bytes());
+        chunk.extend_from_slice(b"INFO");
+        chunk.extend_from_slice(&info);
+        chunk
+    }
+}
+
+pub struct WriteInfoBuilder {
+    info: WriteInfo,
+}
+
+impl WriteInfo {
+    /// Starts from 44.1 kHz mono 16-bit WAV written to "out.wav".
+    pub fn builder() -> WriteInfoBuilder {
+        WriteInfoBuilder {
+            info: WriteInfo {
+                filepath: String::from("out.wav"),
+                sample_rate: 44100,
+                channels: 1,
+                downmix: Downmix::Ignore,
+                format: Format::Wave,
+                sample_format: SampleFormat::I16,
+                normalize: None,
+                dc_block: false,
+                dither: false,
+                oversample: 1,
+                render_rate: None,
+                resampling: Resampling::Sinc,
+                chunk_size: None,
+                loop_points: None,
+                clipping: Clipping::Hard,
+                metadata: Metadata::default(),
+            },
+        }
+    }
+
+    fn layout(&self) -> Layout {
+        Layout { channels: self.channels, downmix: self.downmix }
+    }
+
+    // The rate the song is mixed at, before any oversampling.
+    fn mix_rate(&self) -> u32 {
+        self.render_rate.unwrap_or(self.sample_rate)
+    }
+}
+
+impl WriteInfoBuilder {
+    pub fn filepath(mut self, filepath: &str) -> WriteInfoBuilder {
+        self.info.filepath = String::from(filepath);
+        self
+    }
+
+    pub fn sample_rate(mut self, sample_rate: u32) -> WriteInfoBuilder {
+        self.info.sample_rate = sample_rate;
+        self
+    }
+
+    pub fn channels(mut self, channels: u16) -> WriteInfoBuilder {
+        self.info.channels = channels;
+        self
+    }
+
+    pub fn downmix(mut self, downmix: Downmix) -> WriteInfoBuilder {
+        self.info.downmix = downmix;
+        self
+    }
+
+    /// Shorthand for two channels, or one when `stereo` is false.
+    pub fn stereo(mut self, stereo: bool) -> WriteInfoBuilder {
+        self.info.channels = if stereo {2} else {1};
+        self
+    }
+
+    pub fn format(mut self, format: Format) -> WriteInfoBuilder {
+        self.info.format = format;
+        self
+    }
+
+    pub fn sample_format(mut self, sample_format: SampleFormat) -> WriteInfoBuilder {
+        self.info.sample_format = sample_format;
+        self
+    }
+
+    pub fn normalize(mut self, target: f64) -> WriteInfoBuilder {
+        self.info.normalize = Some(target);
+        self
+    }
+
+    pub fn dc_block(mut self, dc_block: bool) -> WriteInfoBuilder {
+        self.info.dc_block = dc_block;
+        self
+    }
+
+    pub fn dither(mut self, dither: bool) -> WriteInfoBuilder {
+        self.info.dither = dither;
+        self
+    }
+
+    pub fn oversample(mut self, factor: u32) -> WriteInfoBuilder {
+        self.info.oversample = factor;
+        self
+    }
+
+    pub fn render_rate(mut self, render_rate: u32) -> WriteInfoBuilder {
+        self.info.render_rate = Some(render_rate);
+        self
+    }
+
+    pub fn resampling(mut self, resampling: Resampling) -> WriteInfoBuilder {
+        self.info.resampling = resampling;
+        self
+    }
+
+    pub fn chunk_size(mut self, frames: u32) -> WriteInfoBuilder {
+        self.info.chunk_size = Some(frames);
+        self
+    }
+
+    pub fn loop_points(mut self, start: u32, end: u32) -> WriteInfoBuilder {
+        self.info.loop_points = Some((start, end));
+        self
+    }
+
+    pub fn clipping(mut self, clipping: Clipping) -> WriteInfoBuilder {
+        self.info.clipping = clipping;
+        self
+    }
+
+    pub fn metadata(mut self, metadata: Metadata) -> WriteInfoBuilder {
+        self.info.metadata = metadata;
+        self
+    }
+
+    pub fn build(self) -> WriteInfo {
+        self.info
+    }
+}
+
+#[derive(Debug)]
+pub enum UntzError {
+    Io(io::Error),
+    InvalidSampleRate(u32),
+    InvalidParameter(String),
+    InvalidWav(String),
+    #[cfg(feature = "playback")]
+    Playback(String),
+    #[cfg(feature = "midi")]
+    Midi(String),
+}
+
+impl fmt::Display for UntzError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UntzError::Io(err) => write!(f, "I/O error: {}", err),
+            UntzError::InvalidSampleRate(rate) => write!(f, "invalid sample rate: {} Hz", rate),
+            UntzError::InvalidParameter(msg) => write!(f, "invalid parameter: {}", msg),
+            UntzError::InvalidWav(msg) => write!(f, "invalid WAV file: {}", msg),
+            #[cfg(feature = "playback")]
+            UntzError::Playback(msg) => write!(f, "playback error: {}", msg),
+            #[cfg(feature = "midi")]
+            UntzError::Midi(msg) => write!(f, "MIDI error: {}", msg),
+        }
+    }
+}
+
+impl Error for UntzError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            UntzError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for UntzError {
+    fn from(err: io::Error) -> UntzError {
+        UntzError::Io(err)
+    }
+}
+
+// Anything above this is almost certainly a mistake
+const MAX_SAMPLE_RATE: u32 = 768_000;
+
+fn check_finite(what: &str, value: f64) -> Result<(), UntzError> {
+    if value.is_finite() {
+        Ok(())
+    } else {
+        Err(UntzError::InvalidParameter(format!("{} is not finite ({})", what, value)))
+    }
+}
+
+// Breakpoint envelopes need finite values at ascending times within the note
+fn check_breakpoints(what: &str, breakpoints: &[(f64, f64)]) -> Result<(), UntzError> {
+    let mut last_fraction = 0_f64;
+    for (fraction, value) in breakpoints.iter() {
+        check_finite(what, *value)?;
+        if !(*fraction >= last_fraction && *fraction <= 1.0) {
+            return Err(UntzError::InvalidParameter(format!("{} times must be ascending within [0, 1] ({})", what, fraction)));
+        }
+        last_fraction = *fraction;
+    }
+    Ok(())
+}
+
+// Default for gains left out of a serialized song
+#[cfg(feature = "serde")]
+fn unity() -> f64 {
+    1.0
+}
+
+fn overwrite<T>(_curr: T, new: T) -> T {
+    new
+}
+
+fn add<T: std::ops::Add<Output = T>>(curr: T, new: T) -> T {
+    curr + new
+}
+
+#[cfg(feature = "sine-table")]
+const SINE_TABLE_SIZE: usize = 4096;
+
+// Sine of an angle in cycles. With the `sine-table` feature it's read by linear
+// interpolation from a 4096-entry table covering one period, whose error versus
+// f64::sin is at most (2 * PI / 4096) ** 2 / 8, about 3e-7, which is well under
+// one 16-bit LSB. Making one looks the table up, so loops make theirs up front.
+#[derive(Clone, Copy)]
+struct Sine {
+    #[cfg(feature = "sine-table")]
+    table: &'static [f64],
+}
+
+impl Sine {
+    #[cfg(not(feature = "sine-table"))]
+    fn new() -> Sine {
+        Sine {}
+    }
+
+    #[cfg(feature = "sine-table")]
+    fn new() -> Sine {
+        use std::sync::OnceLock;
+        static TABLE: OnceLock<Vec<f64>> = OnceLock::new();
+        // One extra entry so interpolation never needs to wrap around
+        let table = TABLE.get_or_init(|| {
+            (0..=SINE_TABLE_SIZE).map(|i| f64::sin(2.0 * PI * i as f64 / SINE_TABLE_SIZE as f64)).collect()
+        });
+        Sine { table }
+    }
+
+    #[cfg(not(feature = "sine-table"))]
+    fn at(&self, cycles: f64) -> f64 {
+        f64::sin(2.0 * PI * cycles)
+    }
+
+    #[cfg(feature = "sine-table")]
+    fn at(&self, cycles: f64) -> f64 {
+        let pos = (cycles - cycles.floor()) * SINE_TABLE_SIZE as f64;
+        let idx = (pos as usize).min(SINE_TABLE_SIZE - 1);
+        let frac = pos - idx as f64;
+        self.table[idx] + frac * (self.table[idx + 1] - self.table[idx])
+    }
+}
+
+// Polynomial band-limited step, applied around a discontinuity in a waveform
+// with `phase` in [0, 1) and `dt` the phase increment per sample.
+fn poly_blep(phase: f64, dt: f64) -> f64 {
+    if phase < dt {
+        let x = phase / dt;
+        2.0 * x - x * x - 1.0
+    } else if phase > 1.0 - dt {
+        let x = (phase - 1.0) / dt;
+        x * x + 2.0 * x + 1.0
+    } else {
+        0.0
+    }
+}
+
+/// Peak and RMS level of a block of samples, as linear amplitudes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Levels {
+    pub peak: f64,
+    pub rms: f64,
+}
+
+impl Levels {
+    /// Levels relative to full scale; silence is negative infinity.
+    pub fn peak_dbfs(&self) -> f64 {
+        20.0 * self.peak.log10()
+    }
+
+    pub fn rms_dbfs(&self) -> f64 {
+        20.0 * self.rms.log10()
+    }
+}
+
+/// Meters rendered samples, such as the output of `Song::render`.
+pub fn measure(samples: &[f64]) -> Levels {
+    if samples.is_empty() {
+        return Levels { peak: 0.0, rms: 0.0 };
+    }
+    let peak = samples.iter().fold(0_f64, |peak, sample| peak.max(sample.abs()));
+    let mean_square = samples.iter().map(|sample| sample * sample).sum::<f64>() / samples.len() as f64;
+    Levels { peak, rms: mean_square.sqrt() }
+}
+
+/// What happened to the mix on its way into the file. Samples beyond full scale
+/// are clipped (or softened, with `Clipping::Soft`); `max_overshoot` is how far
+/// past 1.0 the loudest of them went, or 0.0 if none did.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ClipReport {
+    pub clipped_samples: usize,
+    pub max_overshoot: f64,
+}
+
+impl ClipReport {
+    fn of(channels: &[Vec<f64>]) -> ClipReport {
+        let mut report = ClipReport { clipped_samples: 0, max_overshoot: 0.0 };
+        for sample in channels.iter().flatten() {
+            if sample.abs() > 1.0 {
+                report.clipped_samples += 1;
+                report.max_overshoot = report.max_overshoot.max(sample.abs() - 1.0);
+            }
+        }
+        report
+    }
+}
+
+// Eighth-order Butterworth, as four biquads with these Qs
+const DECIMATION_QS: [f64; 4] = [0.5098, 0.6013, 0.9000, 2.5629];
+
+// Brings oversampled channels back down to `sample_rate`, low-passing just
+// below the new Nyquist first so nothing above it folds back.
+struct Decimator {
+    filters: Vec<Vec<Biquad>>,
+    factor: u32,
+}
+
+impl Decimator {
+    fn new(num_channels: usize, factor: u32, sample_rate: u32) -> Decimator {
+        let cutoff = 0.45 * sample_rate as f64;
+        let filters = (0..num_channels).map(|_| {
+            DECIMATION_QS.iter()
+                .map(|&q| Biquad::new(FilterType::LowPass, cutoff, q, sample_rate * factor))
+                .collect()
+        }).collect();
+        Decimator { filters, factor }
+    }
+
+    // Every block but the last must be a whole number of output samples long,
+    // so each one starts on a sample that is kept.
+    fn process(&mut self, channels: &[Vec<f64>]) -> Vec<Vec<f64>> {
+        let factor = self.factor as usize;
+        channels.iter().zip(self.filters.iter_mut()).map(|(channel, filters)| {
+            let filtered: Vec<f64> = channel.iter()
+                .map(|&sample| filters.iter_mut().fold(sample, |x, filter| filter.process(x)))
+                .collect();
+            filtered.into_iter().step_by(factor).collect()
+        }).collect()
+    }
+}
+
+// Half the width of the sinc kernel, in zero crossings of the lower rate
+const SINC_ZERO_CROSSINGS: usize = 32;
+// Fraction of the lower Nyquist kept, leaving room for the kernel's roll-off
+const SINC_CUTOFF: f64 = 0.95;
+
+/// Converts samples at `from_rate` to the same sound at `to_rate`. Anything
+/// past either end of `samples` is taken to be silence.
+pub fn resample(samples: &[f64], from_rate: u32, to_rate: u32, quality: Resampling) -> Vec<f64> {
+    let step = from_rate as f64 / to_rate as f64;
+    let at = |idx: isize| if idx >= 0 {samples.get(idx as usize).copied().unwrap_or(0.0)} else {0.0};
+    (0..resampled_len(samples.len(), from_rate, to_rate)).map(|i| {
+        let position = i as f64 * step;
+        let idx = position.floor() as isize;
+        let frac = position - idx as f64;
+        match quality {
+            Resampling::Linear => at(idx) + frac * (at(idx + 1) - at(idx)),
+            Resampling::Sinc => {
+                // Scaled down when going to a lower rate so it also low-passes
+                let scale = SINC_CUTOFF * f64::min(1.0, to_rate as f64 / from_rate as f64);
+                let half_width = SINC_ZERO_CROSSINGS as f64 / scale;
+                let reach = half_width.ceil() as isize;
+                (idx - reach..=idx + reach).map(|k| {
+                    let x = position - k as f64;
+                    if x.abs() >= half_width {
+                        return 0.0;
+                    }
+                    // Blackman window over the kernel's width
+                    let w = 0.42 + 0.5 * f64::cos(PI * x / half_width) + 0.08 * f64::cos(2.0 * PI * x / half_width);
+                    let sinc = if x == 0.0 {1.0} else {f64::sin(PI * scale * x) / (PI * scale * x)};
+                    at(k) * scale * sinc * w
+                }).sum()
+            },
+        }
+    }).collect()
+}
+
+// Samples of `len` at `from_rate` covering the same time at `to_rate`.
+fn resampled_len(len: usize, from_rate: u32, to_rate: u32) -> usize {
+    (len as u64 * to_rate as u64).div_ceil(from_rate as u64) as usize
+}
+
+// One-pole DC blocker, y[n] = x[n] - x[n-1] + r * y[n-1]. The corner sits near
+// 5 Hz, well below anything audible.
+struct DcBlocker {
+    r: f64,
+    // Last input and output of each channel
+    last: Vec<(f64, f64)>,
+}
+
+impl DcBlocker {
+    fn new(num_channels: usize, sample_rate: u32) -> DcBlocker {
+        DcBlocker {
+            r: 1.0 - 2.0 * PI * 5.0 / sample_rate as f64,
+            last: vec![(0.0, 0.0); num_channels],
+        }
+    }
+
+    fn process(&mut self, channels: &mut [Vec<f64>]) {
+        for (channel, (last_in, last_out)) in channels.iter_mut().zip(self.last.iter_mut()) {
+            for sample in channel.iter_mut() {
+                *last_out = *sample - *last_in + self.r * *last_out;
+                *last_in = *sample;
+                *sample = *last_out;
+            }
+        }
+    }
+}
+
+fn peak(channels: &[Vec<f64>]) -> f64 {
+    channels.iter().flatten().fold(0_f64, |peak, sample| peak.max(sample.abs()))
+}
+
+// Scales the channels down so that `peak` lands on `target`. The peak can be
+// measured over more than these channels, such as the whole song for a chunk.
+fn normalize(channels: &mut [Vec<f64>], peak: f64, target: f64) {
+    if peak > target {
+        let scale = target / peak;
+        for sample in channels.iter_mut().flatten() {
+            *sample *= scale;
+        }
+    }
+}
+
+// Everything that affects a note's synthesized samples, as raw bit patterns so
+// it can be hashed. Start and pan are applied afterwards and don't belong here.
+type NoteKey = Vec<u64>;
+
+fn cache_key(note: &Note, duration: f64, glide_from: Option<f64>) -> NoteKey {
+    let mut key = vec![
+        note.freq.to_bits(),
+        glide_from.unwrap_or(note.freq).to_bits(),
+        note.volume.to_bits(),
+        duration.to_bits(),
+        note.vibrato_rate.to_bits(),
+        note.vibrato_depth.to_bits(),
+        note.phase.to_bits(),
+        note.detune_cents.to_bits(),
+    ];
+    for (fraction, semitones) in note.pitch_envelope.iter() {
+        key.extend(&[fraction.to_bits(), semitones.to_bits()]);
+    }
+    if let Some(sweep) = &note.sweep {
+        let kind = match sweep {
+            Sweep::Linear { .. } => 0,
+            Sweep::Exponential { .. } => 1,
+        };
+        key.extend(&[kind, sweep.end_freq().to_bits()]);
+    }
+    if let Some(envelope) = &note.envelope {
+        key.extend(&[
+            envelope.attack.to_bits(),
+            envelope.decay.to_bits(),
+            envelope.sustain.to_bits(),
+            envelope.release.to_bits(),
+        ]);
+    }
+    key
+}
+
+fn merge<T: Copy>(dst: &mut [T], src: &[T], merge_fn: fn(T, T) -> T) {
+    if dst.len() != src.len() {
+        panic!("Mismatched length!");
+    }
+    for i in 0..dst.len() {
+        dst[i] = merge_fn(dst[i], src[i]);
+    }
+}
+
+// Per-channel gains for a note. Pan runs from the first channel at -1.0 to the
+// last at 1.0, and the note is split between the two channels nearest its
+// position with an equal-power law so it keeps the same perceived loudness.
+// With one channel the layout's downmix decides what pan does.
+fn pan_gains(pan: f64, layout: Layout) -> Vec<f64> {
+    let num_channels = layout.channels;
+    let mut gains = vec![0_f64; num_channels as usize];
+    if num_channels == 1 {
+        let pan = pan.clamp(-1.0, 1.0);
+        gains[0] = match layout.downmix {
+            Downmix::Ignore => 1.0,
+            // (cos + sin) / sqrt(2) of the stereo pan angle, written so the
+            // center comes out at exactly 1.0
+            Downmix::Minus3Db => f64::cos(pan * PI / 4.0),
+            Downmix::Minus6Db => 1.0 - 0.5 * pan.abs(),
+        };
+        return gains;
+    }
+    let position = (pan.clamp(-1.0, 1.0) + 1.0) / 2.0 * (num_channels - 1) as f64;
+    let left = (position as usize).min(num_channels as usize - 2);
+    let angle = (position - left as f64) * PI / 2.0;
+    gains[left] = f64::cos(angle);
+    gains[left + 1] = f64::sin(angle);
+    gains
+}
+
+/// Standard concert pitch for A4, in Hz
+pub const A4_FREQ: f64 = 440.0;
+
+/// Parses scientific pitch notation ("A4", "C#5", "Bb3") into an equal-temperament
+/// frequency with A4 = 440 Hz.
+pub fn note_freq(name: &str) -> Option<f64> {
+    note_freq_tuned(name, A4_FREQ)
+}
+
+/// Like `note_freq`, but with A4 tuned to `a4` Hz (e.g. 432.0).
+pub fn note_freq_tuned(name: &str, a4: f64) -> Option<f64> {
+    let mut chars = name.chars();
+    let pitch_class = match chars.next()? {
+        'C' => 0,
+        'D' => 2,
+        'E' => 4,
+        'F' => 5,
+        'G' => 7,
+        'A' => 9,
+        'B' => 11,
+        _ => return None,
+    };
+    let rest = chars.as_str();
+    let (accidental, octave) = if let Some(octave) = rest.strip_prefix('#') {
+        (1, octave)
+    } else if let Some(octave) = rest.strip_prefix('b') {
+        (-1, octave)
+    } else {
+        (0, rest)
+    };
+    let octave: i32 = octave.parse().ok()?;
+    let semitones = (octave - 4) * 12 + pitch_class + accidental - 9;
+    Some(a4 * f64::powf(2.0, semitones as f64 / 12.0))
+}
+
+/// A tuning that repeats every octave, given as each degree's offset above the
+/// octave's root in cents. 12-TET is `Scale::equal(12)` and gives exactly the
+/// same frequencies as `note_freq`.
+#[derive(Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "ScaleCents"))]
+pub struct Scale {
+    cents: Vec<f64>,
+}
+
+// What a scale deserializes from, so it goes through the same checks as
+// `Scale::from_cents`
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+struct ScaleCents {
+    cents: Vec<f64>,
+}
+
+#[cfg(feature = "serde")]
+impl std::convert::TryFrom<ScaleCents> for Scale {
+    type Error = &'static str;
+
+    fn try_from(scale: ScaleCents) -> Result<Scale, Self::Error> {
+        Scale::from_cents(scale.cents).ok_or("a scale needs at least one degree, all finite")
+    }
+}
+
+impl Default for Scale {
+    fn default() -> Scale {
+        Scale::equal(12)
+    }
+}
+
+impl Scale {
+    /// `divisions` equal steps per octave, e.g. 19 for 19-TET.
+    pub fn equal(divisions: u32) -> Scale {
+        let divisions = divisions.max(1);
+        Scale {
+            cents: (0..divisions).map(|step| step as f64 * 1200.0 / divisions as f64).collect(),
+        }
+    }
+
+    /// Degrees in cents above the root, which should come first as 0.0.
+    pub fn from_cents(cents: Vec<f64>) -> Option<Scale> {
+        if cents.is_empty() || cents.iter().any(|step| !step.is_finite()) {
+            return None;
+        }
+        Some(Scale { cents })
+    }
+
+    /// Degrees as frequency ratios to the root, e.g. [1.0, 9.0 / 8.0, 5.0 / 4.0, ...]
+    /// for just intonation.
+    pub fn from_ratios(ratios: &[f64]) -> Option<Scale> {
+        if ratios.iter().any(|&ratio| ratio.is_nan() || ratio <= 0.0) {
+            return None;
+        }
+        Scale::from_cents(ratios.iter().map(|ratio| 1200.0 * ratio.log2()).collect())
+    }
+
+    /// Frequency of scale degree `degree` above `base`, which is degree 0.
+    /// Degrees past the end of the scale continue into higher octaves, and
+    /// negative degrees count down into lower ones.
+    pub fn freq(&self, degree: i32, base: f64) -> f64 {
+        let len = self.cents.len() as i32;
+        let cents = degree.div_euclid(len) as f64 * 1200.0 + self.cents[degree.rem_euclid(len) as usize];
+        base * f64::powf(2.0, cents / 1200.0)
+    }
+
+    /// The first `count` degrees going up from `base`, for feeding to
+    /// `Track::arpeggio`. `Scale::mode(Mode::Major).run(note_freq("C4")?, 8)` is
+    /// C4 up to C5.
+    pub fn run(&self, base: f64, count: usize) -> Vec<f64> {
+        (0..count as i32).map(|degree| self.freq(degree, base)).collect()
+    }
+
+    /// The degrees of `mode` in 12-TET.
+    pub fn mode(mode: Mode) -> Scale {
+        Scale {
+            cents: mode.semitones().iter().map(|&semitones| semitones as f64 * 100.0).collect(),
+        }
+    }
+}
+
+/// Scales and modes picked out of the twelve semitones, for `Scale::mode`
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Mode {
+    Major,
+    /// Natural minor
+    Minor,
+    HarmonicMinor,
+    /// The ascending form, with a raised sixth and seventh
+    MelodicMinor,
+    Dorian,
+    Phrygian,
+    Lydian,
+    Mixolydian,
+    Locrian,
+    MajorPentatonic,
+    MinorPentatonic,
+    Blues,
+    Chromatic,
+}
+
+impl Mode {
+    // Each degree in semitones above the root.
+    fn semitones(&self) -> &'static [u32] {
+        match self {
+            Mode::Major => &[0, 2, 4, 5, 7, 9, 11],
+            Mode::Minor => &[0, 2, 3, 5, 7, 8, 10],
+            Mode::HarmonicMinor => &[0, 2, 3, 5, 7, 8, 11],
+            Mode::MelodicMinor => &[0, 2, 3, 5, 7, 9, 11],
+            Mode::Dorian => &[0, 2, 3, 5, 7, 9, 10],
+            Mode::Phrygian => &[0, 1, 3, 5, 7, 8, 10],
+            Mode::Lydian => &[0, 2, 4, 6, 7, 9, 11],
+            Mode::Mixolydian => &[0, 2, 4, 5, 7, 9, 10],
+            Mode::Locrian => &[0, 1, 3, 5, 6, 8, 10],
+            Mode::MajorPentatonic => &[0, 2, 4, 7, 9],
+            Mode::MinorPentatonic => &[0, 3, 5, 7, 10],
+            Mode::Blues => &[0, 3, 5, 6, 7, 10],
+            Mode::Chromatic => &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11],
+        }
+    }
+}
+
+// Value of a breakpoint envelope at `fraction`, interpolating linearly between
+// breakpoints and holding the end values beyond them.
+fn breakpoint_value(breakpoints: &[(f64, f64)], fraction: f64) -> f64 {
+    let (first, last) = (breakpoints[0], breakpoints[breakpoints.len() - 1]);
+    if fraction <= first.0 {
+        return first.1;
+    }
+    for pair in breakpoints.windows(2) {
+        let ((t0, s0), (t1, s1)) = (pair[0], pair[1]);
+        if fraction < t1 {
+            return s0 + (s1 - s0) * (fraction - t0) / (t1 - t0);
+        }
+    }
+    last.1
+}
+
+/// The MIDI key number for `freq`, with a fractional part for pitches between
+/// keys. Round it to get the nearest key.
+pub fn freq_to_midi(freq: f64) -> f64 {
+    freq_to_midi_tuned(freq, A4_FREQ)
+}
+
+/// Like `freq_to_midi`, but with A4 tuned to `a4` Hz.
+pub fn freq_to_midi_tuned(freq: f64, a4: f64) -> f64 {
+    69.0 + 12.0 * (freq / a4).log2()
+}
+
+impl Note {
+    /// A centered note with no envelope or modulation.
+    pub fn new(freq: f64, volume: f64, start: f64, duration: f64) -> Note {
+        Note {
+            freq,
+            volume,
+            start,
+            duration,
+            envelope: None,
+            pan: 0.0,
+            vibrato_rate: 0.0,
+            vibrato_depth: 0.0,
+            phase: 0.0,
+            detune_cents: 0.0,
+            pitch_envelope: Vec::new(),
+            pan_envelope: Vec::new(),
+            sweep: None,
+            tie: false,
+        }
+    }
+
+    pub fn named(name: &str, volume: f64, start: f64, duration: f64) -> Option<Note> {
+        Note::named_tuned(name, A4_FREQ, volume, start, duration)
+    }
+
+    /// Like `named`, but with A4 tuned to `a4` Hz.
+    pub fn named_tuned(name: &str, a4: f64, volume: f64, start: f64, duration: f64) -> Option<Note> {
+        Some(Note::new(note_freq_tuned(name, a4)?, volume, start, duration))
+    }
+
+    /// A note at MIDI key `number`, where 60 is middle C and 69 is A4 = 440 Hz.
+    pub fn from_midi(number: u8, volume: f64, start: f64, duration: f64) -> Note {
+        Note::from_midi_tuned(number, A4_FREQ, volume, start, duration)
+    }
+
+    /// Like `from_midi`, but with A4 tuned to `a4` Hz.
+    pub fn from_midi_tuned(number: u8, a4: f64, volume: f64, start: f64, duration: f64) -> Note {
+        let freq = a4 * f64::powf(2.0, (number as f64 - 69.0) / 12.0);
+        Note::new(freq, volume, start, duration)
+    }
+
+    /// Starts from a one second A4 at full volume.
+    pub fn builder() -> NoteBuilder {
+        NoteBuilder {
+            note: Note::new(440.0, 1.0, 0.0, 1.0),
+        }
+    }
+}
+
+pub struct NoteBuilder {
+    note: Note,
+}
+
+impl NoteBuilder {
+    pub fn freq(mut self, freq: f64) -> NoteBuilder {
+        self.note.freq = freq;
+        self
+    }
+
+    pub fn volume(mut self, volume: f64) -> NoteBuilder {
+        self.note.volume = volume;
+        self
+    }
+
+    pub fn start(mut self, start: f64) -> NoteBuilder {
+        self.note.start = start;
+        self
+    }
+
+    pub fn duration(mut self, duration: f64) -> NoteBuilder {
+        self.note.duration = duration;
+        self
+    }
+
+    pub fn envelope(mut self, envelope: Envelope) -> NoteBuilder {
+        self.note.envelope = Some(envelope);
+        self
+    }
+
+    pub fn pan(mut self, pan: f64) -> NoteBuilder {
+        self.note.pan = pan;
+        self
+    }
+
+    pub fn vibrato(mut self, rate: f64, depth: f64) -> NoteBuilder {
+        self.note.vibrato_rate = rate;
+        self.note.vibrato_depth = depth;
+        self
+    }
+
+    pub fn phase(mut self, phase: f64) -> NoteBuilder {
+        self.note.phase = phase;
+        self
+    }
+
+    pub fn detune(mut self, cents: f64) -> NoteBuilder {
+        self.note.detune_cents = cents;
+        self
+    }
+
+    pub fn pitch_envelope(mut self, breakpoints: Vec<(f64, f64)>) -> NoteBuilder {
+        self.note.pitch_envelope = breakpoints;
+        self
+    }
+
+    pub fn pan_envelope(mut self, breakpoints: Vec<(f64, f64)>) -> NoteBuilder {
+        self.note.pan_envelope = breakpoints;
+        self
+    }
+
+    pub fn sweep(mut self, sweep: Sweep) -> NoteBuilder {
+        self.note.sweep = Some(sweep);
+        self
+    }
+
+    pub fn tie(mut self, tie: bool) -> NoteBuilder {
+        self.note.tie = tie;
+        self
+    }
+
+    pub fn build(self) -> Note {
+        self.note
+    }
+}
+
+impl Envelope {
+    // The release is taken from the end of the note rather than added after it,
+    // so the envelope always fits inside the note's duration. A release longer
+    // than the note is already underway when the note starts, and the attack
+    // and decay play out under it.
+    fn gain(&self, t: f64, duration: f64) -> f64 {
+        let release_start = (duration - self.release).max(0.0);
+        let held = |t: f64| {
+            if t < self.attack {
+                t / self.attack
+            } else if t < self.attack + self.decay {
+                1.0 - (1.0 - self.sustain) * (t - self.attack) / self.decay
+            } else {
+                self.sustain
+            }
+        };
+        if t < release_start {
+            held(t)
+        } else if self.release > duration {
+            held(t) * (duration - t) / self.release
+        } else {
+            held(release_start) * (1.0 - (t - release_start) / (duration - release_start))
+        }
+    }
+}
+
+// Generates the samples for a single note, before panning and track gain.
+// `amplitude` stands in for the note's volume, already through the track's
+// velocity curve. `glide` is the frequency to slide in from and how long the
+// slide takes.
+fn synthesize(instrument: &Instrument, note: &Note, amplitude: f64, duration: f64, glide: Option<(f64, f64)>, sample_rate: u32, seed: u64) -> Vec<f64> {
+    let mut voice = Voice::new(seed, duration);
+    let mut note_samples = vec![0_f64; (duration * sample_rate as f64) as usize];
+    // Phase is accumulated per sample rather than derived from t so that the
+    // frequency is free to change while the note plays.
+    let mut phase = note.phase.rem_euclid(1.0);
+    let detune = f64::powf(2.0, note.detune_cents / 1200.0);
+    for item in note_samples.iter_mut().enumerate() {
+        let t = item.0 as f64 / sample_rate as f64;
+        voice.time = t;
+        let target = match &note.sweep {
+            Some(sweep) => sweep.freq(note.freq, t / duration) * detune,
+            None => note.freq * detune,
+        };
+        let mut freq = target;
+        if let Some((from, glide_time)) = glide {
+            if t < glide_time {
+                freq = from * f64::powf(target / from, t / glide_time);
+            }
+        }
+        if note.vibrato_depth != 0.0 {
+            let cents = note.vibrato_depth * voice.sine.at(note.vibrato_rate * t);
+            freq *= f64::powf(2.0, cents / 1200.0);
+        }
+        if !note.pitch_envelope.is_empty() {
+            freq *= f64::powf(2.0, breakpoint_value(&note.pitch_envelope, t / duration) / 12.0);
+        }
+        *item.1 = amplitude * instrument.oscillate(phase, freq, sample_rate, &mut voice);
+        if let Some(envelope) = &note.envelope {
+            *item.1 *= envelope.gain(t, duration);
+        }
+        phase = f64::fract(phase + freq / sample_rate as f64);
+    }
+    note_samples
+}
+
+/// The samples of one note on its own, with its volume, envelope and pitch
+/// modulation applied but before any panning or mixing. The start is ignored
+/// and the duration is read as seconds, as on a track without a tempo. Random
+/// instruments always draw the same noise for the same note.
+pub fn render_note(instrument: &Instrument, note: &Note, sample_rate: u32) -> Vec<f64> {
+    synthesize(instrument, note, note.volume, note.duration, None, sample_rate, 0)
+}
+
+// Equal-power gain at sample `pos` of a note `len` long that fades in over its
+// first `fade_in` samples and out over its last `fade_out`.
+fn crossfade_gain(pos: usize, len: usize, fade_in: usize, fade_out: usize) -> f64 {
+    let mut gain = 1.0;
+    if pos < fade_in {
+        gain *= f64::sin(PI / 2.0 * pos as f64 / fade_in as f64);
+    }
+    if pos + fade_out >= len {
+        gain *= f64::cos(PI / 2.0 * (pos + fade_out - len) as f64 / fade_out as f64);
+    }
+    gain
+}
+
+// A track's part of a render done in blocks: the notes still sounding at the
+// end of the last block and the state of its effects. Notes are visited in
+// order of their start, so each block only looks at the notes it can hear.
+struct TrackState {
+    // Position in the song, which only seeds the noise generators
+    track_idx: usize,
+    held: HashMap<usize, Arc<Vec<f64>>>,
+    effects: Vec<EffectState>,
+    // The track's notes with ties joined up, if it has any ties
+    tied: Option<Vec<Note>>,
+    // Where each note starts and how long it sounds, in samples
+    spans: Vec<(usize, usize)>,
+    // Samples each note fades in and out over, if the track crossfades
+    fades: Vec<(usize, usize)>,
+    // Note indices by start sample, and how many of them have been reached
+    order: Vec<usize>,
+    next: usize,
+    // Notes that rang on past the end of the last block
+    playing: Vec<usize>,
+}
+
+// How far apart, in the track's units, a note's end and a tied note's start
+// can be and still join
+const TIE_TOLERANCE: f64 = 1e-9;
+
+impl Track {
+    pub fn new(instrument: Instrument) -> Track {
+        Track {
+            name: String::new(),
+            instrument,
+            notes: Vec::new(),
+            bpm: None,
+            gain: 1.0,
+            velocity_curve: VelocityCurve::Linear,
+            glide: 0.0,
+            tremolo_rate: 0.0,
+            tremolo_depth: 0.0,
+            effects: Vec::new(),
+            filter: None,
+            swing: 0.0,
+            articulation: 1.0,
+            crossfade: false,
+            volume_automation: Vec::new(),
+            muted: false,
+            solo: false,
+        }
+    }
+
+    /// The track's name, or "Track <n>" if it has none, where `n` counts the
+    /// song's tracks from 1, so index 0 is "Track 1".
+    pub fn label(&self, track_idx: usize) -> String {
+        if self.name.is_empty() {
+            format!("Track {}", track_idx + 1)
+        } else {
+            self.name.clone()
+        }
+    }
+
+    pub fn with_tempo(instrument: Instrument, bpm: f64) -> Track {
+        Track {
+            bpm: Some(bpm),
+            ..Track::new(instrument)
+        }
+    }
+
+    /// A click track timed in beats at `bpm`, `bars` bars of `beats_per_bar`
+    /// beats each. Downbeats are higher and louder than the other beats.
+    pub fn metronome(bpm: f64, bars: u32, beats_per_bar: u32) -> Track {
+        let mut track = Track::with_tempo(Instrument::Sine, bpm);
+        // Clicks last the same time at any tempo
+        let click = 0.05 * bpm / 60.0;
+        for beat in 0..bars * beats_per_bar {
+            let (freq, volume) = if beat % beats_per_bar == 0 {(1760.0, 1.0)} else {(880.0, 0.6)};
+            track.note(Note {
+                envelope: Some(Envelope { attack: 0.001, decay: 0.04, sustain: 0.0, release: 0.005 }),
+                ..Note::new(freq, volume, beat as f64, click)
+            });
+        }
+        track
+    }
+
+    /// Converts a time in this track's units into seconds.
+    pub fn seconds(&self, time: f64) -> f64 {
+        match self.bpm {
+            Some(bpm) => time * 60.0 / bpm,
+            None => time,
+        }
+    }
+
+    /// Converts a note's start into seconds, applying swing. Within each beat the
+    /// off-beat eighth moves from halfway to `0.5 + swing / 3`, and everything
+    /// else is stretched linearly around it so note order is preserved.
+    pub fn start_seconds(&self, start: f64) -> f64 {
+        if self.bpm.is_none() || self.swing == 0.0 {
+            return self.seconds(start);
+        }
+        let beat = start.floor();
+        let frac = start - beat;
+        let split = 0.5 + self.swing / 3.0;
+        let swung = if frac < 0.5 {
+            frac * split / 0.5
+        } else {
+            split + (frac - 0.5) * (1.0 - split) / 0.5
+        };
+        self.seconds(beat + swung)
+    }
+
+    // A note's samples on this track, through the track's filter if it has one.
+    fn synthesize_note(&self, note: &Note, amplitude: f64, duration: f64, glide: Option<(f64, f64)>, sample_rate: u32, seed: u64) -> Vec<f64> {
+        let mut note_samples = synthesize(&self.instrument, note, amplitude, duration, glide, sample_rate, seed);
+        if let Some(filter) = &self.filter {
+            filter.apply(&mut note_samples, duration, sample_rate);
+        }
+        note_samples
+    }
+
+    // Where a note starts and how long it sounds, in samples.
+    fn sample_span(&self, note: &Note, sample_rate: u32) -> (usize, usize) {
+        let start = self.start_seconds(note.start);
+        let duration = self.seconds(note.duration) * self.articulation;
+        ((start * sample_rate as f64) as usize, (duration * sample_rate as f64) as usize)
+    }
+
+    // How many samples at the start and end of each note overlap the notes
+    // that start just before and just after it.
+    fn crossfades(spans: &[(usize, usize)]) -> Vec<(usize, usize)> {
+        let mut order: Vec<usize> = (0..spans.len()).filter(|&idx| spans[idx].1 > 0).collect();
+        order.sort_by_key(|&idx| spans[idx].0);
+        let mut fades = vec![(0, 0); spans.len()];
+        for pair in order.windows(2) {
+            let ((prev_start, prev_len), (next_start, next_len)) = (spans[pair[0]], spans[pair[1]]);
+            let overlap = (prev_start + prev_len).saturating_sub(next_start);
+            fades[pair[0]].1 = overlap;
+            fades[pair[1]].0 = overlap.min(next_len);
+        }
+        fades
+    }
+
+    fn stream_state(&self, track_idx: usize, layout: Layout, sample_rate: u32, output_rate: u32) -> TrackState {
+        let tied = self.tied_notes();
+        let notes = tied.as_deref().unwrap_or(&self.notes);
+        let spans: Vec<(usize, usize)> = notes.iter().map(|note| self.sample_span(note, sample_rate)).collect();
+        let mut order: Vec<usize> = (0..spans.len()).collect();
+        order.sort_by_key(|&idx| spans[idx].0);
+        TrackState {
+            track_idx,
+            held: HashMap::new(),
+            effects: self.effects.iter().map(|effect| effect.state(layout.channels as usize, sample_rate, output_rate)).collect(),
+            fades: if self.crossfade {Track::crossfades(&spans)} else {Vec::new()},
+            tied,
+            spans,
+            order,
+            next: 0,
+            playing: Vec::new(),
+        }
+    }
+
+    // A copy of the notes where each tied note's duration has been added to the
+    // note it continues, and is itself cut to nothing, so indices and seeds
+    // stay the same. None if no note is tied.
+    fn tied_notes(&self) -> Option<Vec<Note>> {
+        if !self.notes.iter().any(|note| note.tie) {
+            return None;
+        }
+        let mut notes = self.notes.clone();
+        for idx in 0..notes.len() {
+            if !notes[idx].tie {
+                continue;
+            }
+            let (freq, start) = (notes[idx].freq, notes[idx].start);
+            // A note that's already been joined on ends where the last of its
+            // ties does, so a chain of ties all lands on the first note
+            let continued = (0..idx).find(|&prev| {
+                let prev = &notes[prev];
+                prev.duration > 0.0 && prev.freq == freq && (prev.start + prev.duration - start).abs() < TIE_TOLERANCE
+            });
+            if let Some(prev) = continued {
+                notes[prev].duration += notes[idx].duration;
+                notes[idx].duration = 0.0;
+            }
+        }
+        Some(notes)
+    }
+
+    // The frequency the note at `note_idx` glides from, if the track glides.
+    // There's no gliding up from a silent note, since the exponential sweep
+    // can't start at 0 Hz.
+    fn glide_from(&self, notes: &[Note], note_idx: usize) -> Option<f64> {
+        match note_idx {
+            0 => None,
+            _ if self.glide <= 0.0 => None,
+            _ if notes[note_idx - 1].freq <= 0.0 => None,
+            _ => Some(notes[note_idx - 1].freq),
+        }
+    }
+
+    // Mixes this track's notes into one buffer per channel, covering the
+    // `num_samples` samples from `first_sample` on. `on_note` is called as each
+    // note is finished. `state` carries over from the previous window when
+    // rendering in blocks.
+    fn render(&self, state: &mut TrackState, sample_rate: u32, layout: Layout, first_sample: usize, num_samples: usize, on_note: Option<&(dyn Fn() + Sync)>) -> Vec<Vec<f64>> {
+        let mut sample_data = vec![vec![0_f64; num_samples]; layout.channels as usize];
+        let window_end = first_sample + num_samples;
+        let notes = state.tied.as_deref().unwrap_or(&self.notes);
+        // The notes still ringing from the last block and those starting in this
+        // one, mixed in index order so the sum doesn't depend on the block size
+        let mut due = std::mem::take(&mut state.playing);
+        while state.next < state.order.len() && state.spans[state.order[state.next]].0 < window_end {
+            due.push(state.order[state.next]);
+            state.next += 1;
+        }
+        due.sort_unstable();
+        // Repeated notes are only synthesized once, except for random instruments
+        // since each note has its own seed. Counting how many due notes share
+        // each key lets the cache hold only repeated notes, and only until
+        // their last use.
+        let mut cache: HashMap<NoteKey, Arc<Vec<f64>>> = HashMap::new();
+        let mut uses: HashMap<NoteKey, usize> = HashMap::new();
+        if !self.instrument.is_random() {
+            for &note_idx in due.iter().filter(|note_idx| !state.held.contains_key(note_idx)) {
+                let (note_start, note_len) = state.spans[note_idx];
+                if note_len > 0 && note_start + note_len > first_sample {
+                    let note = &notes[note_idx];
+                    let duration = self.seconds(note.duration) * self.articulation;
+                    *uses.entry(cache_key(note, duration, self.glide_from(notes, note_idx))).or_insert(0) += 1;
+                }
+            }
+        }
+        for note_idx in due {
+            let note = &notes[note_idx];
+            let duration = self.seconds(note.duration) * self.articulation;
+            // Notes too short to span a single sample, or over before the window,
+            // contribute nothing
+            let (note_start, note_len) = state.spans[note_idx];
+            if note_len == 0 || note_start + note_len <= first_sample {
+                if let Some(on_note) = on_note {
+                    on_note();
+                }
+                continue;
+            }
+            let seed = ((state.track_idx as u64) << 32) | note_idx as u64;
+            let glide_from = self.glide_from(notes, note_idx);
+            let glide = glide_from.map(|from| (from, self.glide));
+            let amplitude = self.velocity_curve.amplitude(note.volume);
+            let note_samples = if let Some(held) = state.held.get(&note_idx) {
+                Arc::clone(held)
+            } else if self.instrument.is_random() {
+                Arc::new(self.synthesize_note(note, amplitude, duration, glide, sample_rate, seed))
+            } else {
+                let key = cache_key(note, duration, glide_from);
+                let remaining = uses.get_mut(&key).map_or(0, |count| {
+                    *count -= 1;
+                    *count
+                });
+                let samples = cache.remove(&key)
+                    .unwrap_or_else(|| Arc::new(self.synthesize_note(note, amplitude, duration, glide, sample_rate, seed)));
+                if remaining > 0 {
+                    cache.insert(key, Arc::clone(&samples));
+                }
+                samples
+            };
+            // Notes that ring on into the next window are kept until they end
+            let rings_on = note_start + note_samples.len() > window_end;
+            if rings_on {
+                state.held.insert(note_idx, Arc::clone(&note_samples));
+                state.playing.push(note_idx);
+            } else {
+                state.held.remove(&note_idx);
+            }
+            // Clip the note to the window. Even for a whole song, rounding of
+            // start and duration can disagree with the ceil'd song length.
+            let skip = first_sample.saturating_sub(note_start);
+            let start_idx = note_start.max(first_sample) - first_sample;
+            let end_idx = (note_start + note_samples.len()).min(window_end) - first_sample;
+            let note_samples = &note_samples[skip..skip + end_idx - start_idx];
+            let faded: Vec<f64>;
+            let note_samples = match state.fades.get(note_idx) {
+                Some(&(fade_in, fade_out)) if fade_in > 0 || fade_out > 0 => {
+                    faded = note_samples.iter().enumerate()
+                        .map(|(j, sample)| sample * crossfade_gain(skip + j, note_len, fade_in, fade_out))
+                        .collect();
+                    &faded
+                },
+                _ => note_samples,
+            };
+            if note.pan_envelope.is_empty() {
+                for (channel, gain) in sample_data.iter_mut().zip(pan_gains(note.pan, layout)) {
+                    let panned: Vec<f64> = note_samples.iter().map(|sample| self.gain * gain * sample).collect();
+                    merge(&mut channel[start_idx..end_idx], &panned, add);
+                }
+            } else {
+                for (j, sample) in note_samples.iter().enumerate() {
+                    let pan = breakpoint_value(&note.pan_envelope, (skip + j) as f64 / note_len as f64);
+                    for (channel, gain) in sample_data.iter_mut().zip(pan_gains(pan, layout)) {
+                        channel[start_idx + j] += self.gain * gain * sample;
+                    }
+                }
+            }
+            if let (false, Some(on_note)) = (rings_on, on_note) {
+                on_note();
+            }
+        }
+        if self.tremolo_depth != 0.0 {
+            for channel in sample_data.iter_mut() {
+                for (i, sample) in channel.iter_mut().enumerate() {
+                    let t = (first_sample + i) as f64 / sample_rate as f64;
+                    *sample *= 1.0 - self.tremolo_depth * (0.5 - 0.5 * f64::cos(2.0 * PI * self.tremolo_rate * t));
+                }
+            }
+        }
+        if !self.volume_automation.is_empty() {
+            let breakpoints: Vec<(f64, f64)> = self.volume_automation.iter()
+                .map(|&(time, gain)| (self.seconds(time), gain))
+                .collect();
+            for channel in sample_data.iter_mut() {
+                for (i, sample) in channel.iter_mut().enumerate() {
+                    *sample *= breakpoint_value(&breakpoints, (first_sample + i) as f64 / sample_rate as f64);
+                }
+            }
+        }
+        for (effect, effect_state) in self.effects.iter().zip(state.effects.iter_mut()) {
+            effect.apply_block(effect_state, &mut sample_data, sample_rate, first_sample);
+        }
+        sample_data
+    }
+
+    pub fn note(&mut self, note: Note) {
+        self.notes.push(note);
+    }
+
+    /// Adds one note per frequency, all sounding together.
+    pub fn chord(&mut self, freqs: &[f64], volume: f64, start: f64, duration: f64) {
+        for &freq in freqs.iter() {
+            self.note(Note::new(freq, volume, start, duration));
+        }
+    }
+
+    /// Adds one note per frequency, each starting as the previous one ends.
+    pub fn arpeggio(&mut self, freqs: &[f64], volume: f64, start: f64, note_duration: f64) {
+        for (i, &freq) in freqs.iter().enumerate() {
+            self.note(Note::new(freq, volume, start + i as f64 * note_duration, note_duration));
+        }
+    }
+
+    /// Makes the track's notes play `times` times in all, each pass starting
+    /// `interval` (in the track's units) after the one before. Repeating once
+    /// leaves the track as it is, and zero times empties it.
+    pub fn repeat(&mut self, times: usize, interval: f64) {
+        let pattern = std::mem::take(&mut self.notes);
+        for pass in 0..times {
+            for note in pattern.iter() {
+                self.notes.push(Note { start: note.start + pass as f64 * interval, ..note.clone() });
+            }
+        }
+    }
+
+    /// Nudges every note's start by up to `timing_jitter` (in the track's units)
+    /// either way, and scales its volume by up to `volume_jitter` as a fraction
+    /// either way, so 0.1 means within 10%. Notes are kept from starting before
+    /// zero. The same seed always gives the same result.
+    pub fn humanize(&mut self, timing_jitter: f64, volume_jitter: f64, seed: u64) {
+        let mut rng = Rng::new(seed);
+        for note in self.notes.iter_mut() {
+            let timing = 2.0 * rng.next_f64() - 1.0;
+            let volume = 2.0 * rng.next_f64() - 1.0;
+            if timing_jitter != 0.0 {
+                note.start = (note.start + timing_jitter * timing).max(0.0);
+            }
+            if volume_jitter != 0.0 {
+                note.volume *= 1.0 + volume_jitter * volume;
+            }
+        }
+    }
+
+    /// Mirrors the notes in time within the span they occupy, so the last note
+    /// to end becomes the first to start. Only the arrangement is reversed, not
+    /// each note's sound; for that, use `Effect::Reverse`. Reversing twice
+    /// gives back the original notes, up to rounding.
+    pub fn reverse(&mut self) {
+        let first_start = self.notes.iter().map(|note| note.start).fold(f64::INFINITY, f64::min);
+        let last_end = self.notes.iter().map(|note| note.start + note.duration).fold(f64::NEG_INFINITY, f64::max);
+        for note in self.notes.iter_mut() {
+            note.start = first_start + last_end - (note.start + note.duration);
+        }
+        self.notes.reverse();
+    }
+
+    /// Moves note starts toward the nearest multiple of `grid`, in the track's
+    /// units. A `strength` of 1.0 snaps fully and 0.5 goes halfway. Notes already
+    /// on the grid (to within rounding error) are left exactly as they are, and
+    /// a grid that isn't positive does nothing.
+    pub fn quantize(&mut self, grid: f64, strength: f64) {
+        if grid.is_nan() || grid <= 0.0 {
+            return;
+        }
+        for note in self.notes.iter_mut() {
+            let offset = (note.start / grid).round() * grid - note.start;
+            if offset.abs() > grid * 1e-9 {
+                note.start += strength * offset;
+            }
+        }
+    }
+
+    /// Moves every note, and the volume automation with them, `delta` later in
+    /// the track's units, or earlier if it's negative. Anything that would
+    /// move before zero is clamped to zero, so shifting back and forth again
+    /// can bunch notes up at the start.
+    pub fn shift(&mut self, delta: f64) {
+        for note in self.notes.iter_mut() {
+            note.start = (note.start + delta).max(0.0);
+        }
+        for (time, _) in self.volume_automation.iter_mut() {
+            *time = (*time + delta).max(0.0);
+        }
+    }
+}
+
+// Everything a render done in blocks carries from one block to the next.
+struct SongState {
+    tracks: Vec<TrackState>,
+    effects: Vec<EffectState>,
+    // When set, only this track is heard, and without the song's effects,
+    // master volume or fades
+    stem: Option<usize>,
+}
+
+impl Default for Song {
+    fn default() -> Song {
+        Song::new()
+    }
+}
+
+impl Song {
+    pub fn new() -> Song {
+        Song {
+            tracks: Vec::new(),
+            master: 1.0,
+            effects: Vec::new(),
+            tail: 0.0,
+            fade_in: 0.0,
+            fade_out: 0.0,
+        }
+    }
+
+    pub fn track(&mut self, track: Track) {
+        self.tracks.push(track);
+    }
+
+    /// Shifts every note's pitch by a number of equal-tempered semitones.
+    pub fn transpose(&mut self, semitones: i32) {
+        let ratio = f64::powf(2.0, semitones as f64 / 12.0);
+        for track in self.tracks.iter_mut() {
+            for note in track.notes.iter_mut() {
+                note.freq *= ratio;
+            }
+        }
+    }
+
+    /// Shifts every track `delta` seconds later, converted to beats for tracks
+    /// with a tempo. As with `Track::shift`, nothing moves before zero.
+    pub fn shift(&mut self, delta: f64) {
+        for track in self.tracks.iter_mut() {
+            let delta = match track.bpm {
+                Some(bpm) => delta * bpm / 60.0,
+                None => delta,
+            };
+            track.shift(delta);
+        }
+    }
+
+    /// Seconds until the last note ends, not counting the tail.
+    pub fn duration(&self) -> f64 {
+        let mut total_length = 0_f64;
+        for track in self.tracks.iter() {
+            for note in track.notes.iter() {
+                let end_time = track.start_seconds(note.start) + track.seconds(note.duration) * track.articulation;
+                if end_time > total_length {
+                    total_length = end_time;
+                }
+            }
+        }
+        total_length
+    }
+
+    // Mixes every track into one unclamped buffer per channel.
+    fn render_channels(&self, sample_rate: u32, layout: Layout, progress: Option<&mut (dyn FnMut(f64) + Send)>) -> Vec<Vec<f64>> {
+        // A song with no notes (and no tail) has zero samples, but still gets one
+        // empty buffer per channel so it encodes to a valid, silent file.
+        self.render_window(sample_rate, layout, 0, self.num_samples(sample_rate), progress)
+    }
+
+    // Length of the full render, tail included.
+    fn num_samples(&self, sample_rate: u32) -> usize {
+        ((self.duration() + self.tail) * sample_rate as f64).ceil() as usize
+    }
+
+    // Frames in the file `info` describes, after any oversampling and resampling.
+    fn num_frames(&self, info: &WriteInfo) -> usize {
+        let mixed = self.num_samples(info.mix_rate() * info.oversample).div_ceil(info.oversample as usize);
+        resampled_len(mixed, info.mix_rate(), info.sample_rate)
+    }
+
+    // Renders `num_samples` samples starting `first_sample` into the song. Notes
+    // and fades line up with the full song, but effects start fresh at the
+    // window's edge, so echoes from before it aren't heard.
+    fn render_window(&self, sample_rate: u32, layout: Layout, first_sample: usize, num_samples: usize, progress: Option<&mut (dyn FnMut(f64) + Send)>) -> Vec<Vec<f64>> {
+        let mut state = self.stream_state(layout, sample_rate, sample_rate);
+        self.render_block(&mut state, sample_rate, layout, first_sample, num_samples, progress)
+    }
+
+    // Fresh state for rendering at `sample_rate`, for output that will end up at
+    // `output_rate`.
+    fn stream_state(&self, layout: Layout, sample_rate: u32, output_rate: u32) -> SongState {
+        SongState {
+            tracks: self.tracks.iter().enumerate().map(|(track_idx, track)| track.stream_state(track_idx, layout, sample_rate, output_rate)).collect(),
+            effects: self.effects.iter().map(|effect| effect.state(layout.channels as usize, sample_rate, output_rate)).collect(),
+            stem: None,
+        }
+    }
+
+    // Like `render_window`, but picks up where the window before it left off, so
+    // consecutive blocks join up seamlessly.
+    fn render_block(&self, state: &mut SongState, sample_rate: u32, layout: Layout, first_sample: usize, num_samples: usize, progress: Option<&mut (dyn FnMut(f64) + Send)>) -> Vec<Vec<f64>> {
+        // Tracks are rendered independently and then summed in track order, so the
+        // result is bit-identical whether or not they were rendered in parallel.
+        #[cfg(feature = "rayon")]
+        let tracks = self.tracks.par_iter().zip(state.tracks.par_iter_mut());
+        #[cfg(not(feature = "rayon"))]
+        let tracks = self.tracks.iter().zip(state.tracks.iter_mut());
+        // Track indices still count silenced tracks, so muting one doesn't reseed the others
+        let any_solo = self.tracks.iter().any(|track| track.solo);
+        let stem = state.stem;
+        let audible = |track_idx: usize, track: &Track| match stem {
+            Some(stem) => track_idx == stem,
+            None => if any_solo {track.solo} else {!track.muted},
+        };
+        // Progress is counted in notes, shared between tracks on any thread
+        let total_notes: usize = self.tracks.iter().enumerate()
+            .filter(|(track_idx, track)| audible(*track_idx, track))
+            .map(|(_, track)| track.notes.len())
+            .sum();
+        let progress = progress.map(|progress| Mutex::new((0_usize, progress)));
+        let on_note = || {
+            if let Some(progress) = &progress {
+                let mut progress = progress.lock().unwrap();
+                progress.0 += 1;
+                let fraction = progress.0 as f64 / total_notes as f64;
+                (progress.1)(fraction);
+            }
+        };
+        let on_note: Option<&(dyn Fn() + Sync)> = progress.as_ref().map(|_| &on_note as &(dyn Fn() + Sync));
+        let track_data: Vec<Vec<Vec<f64>>> = tracks
+            .filter(|(track, track_state)| audible(track_state.track_idx, track))
+            .map(|(track, track_state)| track.render(track_state, sample_rate, layout, first_sample, num_samples, on_note))
+            .collect();
+
+        let mut sample_data = vec![vec![0_f64; num_samples]; layout.channels as usize];
+        for track_channels in track_data.iter() {
+            for (channel, track_channel) in sample_data.iter_mut().zip(track_channels) {
+                merge(channel, track_channel, add);
+            }
+        }
+        if stem.is_some() {
+            return sample_data;
+        }
+        for (effect, effect_state) in self.effects.iter().zip(state.effects.iter_mut()) {
+            effect.apply_block(effect_state, &mut sample_data, sample_rate, first_sample);
+        }
+        for channel in sample_data.iter_mut() {
+            for sample in channel.iter_mut() {
+                *sample *= self.master;
+            }
+        }
+        // Fades are placed against the whole song, not the window
+        let song_samples = self.num_samples(sample_rate);
+        let fade_in_len = ((self.fade_in * sample_rate as f64) as usize).min(song_samples);
+        let fade_out_len = ((self.fade_out * sample_rate as f64) as usize).min(song_samples);
+        for channel in sample_data.iter_mut() {
+            for (i, sample) in channel.iter_mut().enumerate() {
+                let from_start = first_sample + i;
+                if from_start < fade_in_len {
+                    *sample *= from_start as f64 / fade_in_len as f64;
+                }
+                // Counted so the last sample of the song is 0
+                let from_end = song_samples.saturating_sub(from_start + 1);
+                if from_end < fade_out_len {
+                    *sample *= from_end as f64 / fade_out_len as f64;
+                }
+            }
+        }
+        sample_data
+    }
+
+    /// Renders the song to mono samples in [-1.0, 1.0], exactly as `write` would
+    /// quantize them.
+    pub fn render(&self, sample_rate: u32) -> Vec<f64> {
+        let mut samples = self.render_channels(sample_rate, Layout { channels: 1, downmix: Downmix::Ignore }, None).remove(0);
+        for sample in samples.iter_mut() {
+            *sample = sample.clamp(-1.0, 1.0);
+        }
+        samples
+    }
+
+    /// Like `render`, but only the part of the song from `t_start` to `t_end`
+    /// seconds, which comes out `(t_end - t_start) * sample_rate` samples long.
+    /// Notes straddling either edge are cut off there.
+    pub fn render_range(&self, sample_rate: u32, t_start: f64, t_end: f64) -> Vec<f64> {
+        let first_sample = (t_start.max(0.0) * sample_rate as f64).round() as usize;
+        let num_samples = ((t_end - t_start).max(0.0) * sample_rate as f64).round() as usize;
+        let mut samples = self.render_window(sample_rate, Layout { channels: 1, downmix: Downmix::Ignore }, first_sample, num_samples, None).remove(0);
+        for sample in samples.iter_mut() {
+            *sample = sample.clamp(-1.0, 1.0);
+        }
+        samples
+    }
+
+    /// Catches parameters that would otherwise silently produce a corrupt render.
+    pub fn validate(&self, info: &WriteInfo) -> Result<(), UntzError> {
+        if info.sample_rate == 0 || info.sample_rate > MAX_SAMPLE_RATE {
+            return Err(UntzError::InvalidSampleRate(info.sample_rate));
+        }
+        if info.channels == 0 {
+            return Err(UntzError::InvalidParameter(String::from("there must be at least one channel")));
+        }
+        if let Some(render_rate) = info.render_rate {
+            if render_rate == 0 || render_rate > MAX_SAMPLE_RATE {
+                return Err(UntzError::InvalidSampleRate(render_rate));
+            }
+            if info.chunk_size.is_some() {
+                return Err(UntzError::InvalidParameter(String::from("a song rendered at another rate can't be written in chunks")));
+            }
+        }
+        if info.oversample == 0 || info.mix_rate() as u64 * info.oversample as u64 > MAX_SAMPLE_RATE as u64 {
+            return Err(UntzError::InvalidParameter(format!("oversampling by {} is out of range at {} Hz", info.oversample, info.mix_rate())));
+        }
+        if let (Format::Aiff, SampleFormat::F32) = (&info.format, &info.sample_format) {
+            return Err(UntzError::InvalidParameter(String::from("AIFF does not support float samples")));
+        }
+        if let Some(target) = info.normalize {
+            if !(target.is_finite() && target > 0.0) {
+                return Err(UntzError::InvalidParameter(format!("normalize target must be positive ({})", target)));
+            }
+        }
+        if let Some((start, end)) = info.loop_points {
+            let num_frames = self.num_frames(info);
+            if start > end || end as usize >= num_frames {
+                return Err(UntzError::InvalidParameter(format!("loop from frame {} to {} doesn't fit in the song's {} frames", start, end, num_frames)));
+            }
+        }
+        // WAV and AIFF store their sizes in 32 bits, so a longer file would get a
+        // header that doesn't match its data
+        if !matches!(info.format, Format::RawPcm) {
+            let (sample_bytes, _) = sample_layout(&info.sample_format);
+            let data_size = self.num_frames(info) as u64 * info.channels as u64 * sample_bytes as u64;
+            let header_size = match info.format {
+                Format::Aiff => aiff_header(info, 0).len(),
+                _ => wave_header(info, 0).len(),
+            };
+            // Plus a pad byte
+            if data_size + header_size as u64 + 1 > u32::MAX as u64 {
+                return Err(UntzError::InvalidParameter(format!("{} bytes of samples is too many for one file; use raw output or a shorter song", data_size)));
+            }
+        }
+        if let Some(chunk_size) = info.chunk_size {
+            if chunk_size == 0 {
+                return Err(UntzError::InvalidParameter(String::from("chunk size must be at least one frame")));
+            }
+            let effects = self.effects.iter().chain(self.tracks.iter().flat_map(|track| track.effects.iter()));
+            if effects.into_iter().any(|effect| matches!(effect, Effect::Reverse)) {
+                return Err(UntzError::InvalidParameter(String::from("the reverse effect can't be rendered in chunks")));
+            }
+        }
+        check_finite("master", self.master)?;
+        for (what, value) in [("tail", self.tail), ("fade_in", self.fade_in), ("fade_out", self.fade_out)].iter() {
+            if !(value.is_finite() && *value >= 0.0) {
+                return Err(UntzError::InvalidParameter(format!("{} must be non-negative ({})", what, value)));
+            }
+        }
+        for effect in self.effects.iter() {
+            effect.validate(info.mix_rate())?;
+        }
+        for (track_idx, track) in self.tracks.iter().enumerate() {
+            let label = track.label(track_idx);
+            check_finite(&format!("{} gain", label), track.gain)?;
+            check_finite(&format!("{} glide", label), track.glide)?;
+            match track.velocity_curve {
+                VelocityCurve::Linear => {},
+                VelocityCurve::Exponential { curvature } => check_finite(&format!("{} velocity curvature", label), curvature)?,
+                VelocityCurve::Decibels { range } => {
+                    if !(range.is_finite() && range >= 0.0) {
+                        return Err(UntzError::InvalidParameter(format!("{} velocity range must be non-negative ({})", label, range)));
+                    }
+                },
+            }
+            if !(track.articulation.is_finite() && track.articulation > 0.0) {
+                return Err(UntzError::InvalidParameter(format!("{} articulation must be positive ({})", label, track.articulation)));
+            }
+            let mut last_time = 0_f64;
+            for (time, gain) in track.volume_automation.iter() {
+                check_finite(&format!("{} volume automation", label), *gain)?;
+                if !(time.is_finite() && *time >= last_time) {
+                    return Err(UntzError::InvalidParameter(format!("{} volume automation times must be ascending and non-negative ({})", label, time)));
+                }
+                last_time = *time;
+            }
+            if !(track.swing >= 0.0 && track.swing <= 1.0) {
+                return Err(UntzError::InvalidParameter(format!("{} swing must be in [0, 1] ({})", label, track.swing)));
+            }
+            check_finite(&format!("{} tremolo_rate", label), track.tremolo_rate)?;
+            if !(track.tremolo_depth >= 0.0 && track.tremolo_depth <= 1.0) {
+                return Err(UntzError::InvalidParameter(format!("{} tremolo_depth must be in [0, 1] ({})", label, track.tremolo_depth)));
+            }
+            track.instrument.validate()?;
+            if let Some(filter) = &track.filter {
+                filter.validate(&label)?;
+            }
+            for effect in track.effects.iter() {
+                effect.validate(info.mix_rate())?;
+            }
+            if let Some(bpm) = track.bpm {
+                if !(bpm.is_finite() && bpm > 0.0) {
+                    return Err(UntzError::InvalidParameter(format!("{} bpm must be positive ({})", label, bpm)));
+                }
+            }
+            for (note_idx, note) in track.notes.iter().enumerate() {
+                let what = |field: &str| format!("{} note {} {}", label, note_idx, field);
+                check_finite(&what("freq"), note.freq)?;
+                check_finite(&what("volume"), note.volume)?;
+                check_finite(&what("start"), note.start)?;
+                check_finite(&what("duration"), note.duration)?;
+                check_finite(&what("pan"), note.pan)?;
+                check_finite(&what("vibrato_rate"), note.vibrato_rate)?;
+                check_finite(&what("vibrato_depth"), note.vibrato_depth)?;
+                check_finite(&what("phase"), note.phase)?;
+                check_finite(&what("detune"), note.detune_cents)?;
+                check_breakpoints(&what("pitch envelope"), &note.pitch_envelope)?;
+                check_breakpoints(&what("pan envelope"), &note.pan_envelope)?;
+                if let Some(sweep) = note.sweep {
+                    check_finite(&what("sweep end_freq"), sweep.end_freq())?;
+                    if let Sweep::Exponential { end_freq } = sweep {
+                        if !(note.freq > 0.0 && end_freq > 0.0) {
+                            return Err(UntzError::InvalidParameter(format!("{} must go between positive frequencies ({} to {})", what("sweep"), note.freq, end_freq)));
+                        }
+                    }
+                }
+                if note.start < 0.0 {
+                    return Err(UntzError::InvalidParameter(format!("{} is negative ({})", what("start"), note.start)));
+                }
+                if note.duration < 0.0 {
+                    return Err(UntzError::InvalidParameter(format!("{} is negative ({})", what("duration"), note.duration)));
+                }
+                if let Some(envelope) = &note.envelope {
+                    for (field, value) in [
+                        ("attack", envelope.attack),
+                        ("decay", envelope.decay),
+                        ("sustain", envelope.sustain),
+                        ("release", envelope.release),
+                    ].iter() {
+                        check_finite(&what(&format!("envelope {}", field)), *value)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn write(&self, info: &WriteInfo) -> Result<ClipReport, UntzError> {
+        let mut file = File::create(&info.filepath)?;
+        self.write_to(&mut file, info)
+    }
+
+    /// Like `write`, but calls `progress` with the fraction of the song done so
+    /// far. It's reported once per note rendered, in the order notes finish,
+    /// and once more with 1.0 after the file has been written; the song effects
+    /// and encoding in between aren't broken down further. With a chunk size
+    /// it's reported once per chunk written instead.
+    pub fn write_with_progress<F: FnMut(f64) + Send>(&self, info: &WriteInfo, mut progress: F) -> Result<ClipReport, UntzError> {
+        let mut file = File::create(&info.filepath)?;
+        let report = self.encode(&mut file, info, Some(&mut progress), None)?;
+        progress(1.0);
+        Ok(report)
+    }
+
+    /// Encodes the song into any sink; `info.filepath` is ignored.
+    pub fn write_to<W: Write>(&self, writer: &mut W, info: &WriteInfo) -> Result<ClipReport, UntzError> {
+        self.encode(writer, info, None, None)
+    }
+
+    /// Writes each track to a file of its own for mixing elsewhere, named after
+    /// `info.filepath` with the track's label added, so "song.wav" becomes
+    /// "song-bass.wav" for a track named "bass" and "song-Track 2.wav" for an
+    /// unnamed second track. Tracks should have distinct names, as a stem
+    /// overwrites any earlier one of the same name. Every stem runs the length
+    /// of the whole song and includes its track's effects, but none of the
+    /// master bus: the song's effects, master volume, fades, normalizing and DC
+    /// blocking are all left out. Muting and soloing are ignored.
+    pub fn write_stems(&self, info: &WriteInfo) -> Result<Vec<ClipReport>, UntzError> {
+        self.validate(info)?;
+        (0..self.tracks.len()).map(|track_idx| {
+            let label = self.tracks[track_idx].label(track_idx);
+            let mut file = File::create(stem_path(&info.filepath, &label))?;
+            self.encode(&mut file, info, None, Some(track_idx))
+        }).collect()
+    }
+
+    // Encodes the whole song, or only one track of it as a stem.
+    fn encode<W: Write>(&self, writer: &mut W, info: &WriteInfo, mut progress: Option<&mut (dyn FnMut(f64) + Send)>, stem: Option<usize>) -> Result<ClipReport, UntzError> {
+        self.validate(info)?;
+        let mut renderer = Renderer::new(self, info, stem);
+        let num_frames = renderer.num_frames;
+        // The peak has to be known before the first block is written, so a
+        // chunked render makes an extra pass just to find it
+        let mut song_peak = None;
+        // Stems keep their levels from the mix rather than each being normalized
+        let normalize_to = if stem.is_none() {info.normalize} else {None};
+        if let (Some(_), Some(_)) = (normalize_to, info.chunk_size) {
+            let mut first_pass = Renderer::new(self, info, stem);
+            let mut max = 0_f64;
+            while let Some(block) = first_pass.next_block(None) {
+                max = max.max(peak(&block));
+            }
+            song_peak = Some(max);
+        }
+
+        let (header, big_endian) = match info.format {
+            Format::Wave => (wave_header(info, num_frames), false),
+            Format::Aiff => (aiff_header(info, num_frames), true),
+            Format::RawPcm => (Vec::new(), false),
+        };
+        writer.write_all(&header)?;
+        let mut report = ClipReport { clipped_samples: 0, max_overshoot: 0.0 };
+        // Fixed seed, so dithered output is still reproducible
+        let mut rng = Rng::new(DITHER_SEED);
+        loop {
+            // A whole render reports progress per note, a chunked one per block
+            let block_progress = match (info.chunk_size, progress.as_mut()) {
+                (None, Some(progress)) => Some(&mut **progress as &mut (dyn FnMut(f64) + Send)),
+                _ => None,
+            };
+            let mut block = match renderer.next_block(block_progress) {
+                Some(block) => block,
+                None => break,
+            };
+            if let Some(target) = normalize_to {
+                let block_peak = song_peak.unwrap_or_else(|| peak(&block));
+                normalize(&mut block, block_peak, target);
+            }
+            let block_report = ClipReport::of(&block);
+            report.clipped_samples += block_report.clipped_samples;
+            report.max_overshoot = report.max_overshoot.max(block_report.max_overshoot);
+            writer.write_all(&encode_samples(&block, info, big_endian, &mut rng))?;
+            if let (Some(_), Some(progress)) = (info.chunk_size, progress.as_mut()) {
+                progress(renderer.frames_done() as f64 / num_frames as f64);
+            }
+        }
+        // RIFF and IFF chunks must be word aligned. The pad byte follows the
+        // sample data but isn't counted in its chunk's size, only in the
+        // enclosing chunk's.
+        let (sample_bytes, _) = sample_layout(&info.sample_format);
+        let data_size = num_frames * info.channels as usize * sample_bytes as usize;
+        if !matches!(info.format, Format::RawPcm) && !data_size.is_multiple_of(2) {
+            writer.write_all(&[0_u8])?;
+        }
+        writer.flush()?;
+
+        Ok(report)
+    }
+
+    /// Reads a standard MIDI file, with one sine track per MIDI channel that has
+    /// notes. See `from_midi_bytes`.
+    #[cfg(feature = "midi")]
+    pub fn from_midi(path: &str) -> Result<Song, UntzError> {
+        let bytes = std::fs::read(path)?;
+        Song::from_midi_bytes(&bytes)
+    }
+
+    /// Builds a song from the contents of a standard MIDI file. Each note-on and
+    /// its matching note-off become one note, with the key's equal-temperament
+    /// frequency and a volume of velocity / 127, timed in seconds through the
+    /// file's tempo changes. Overlapping notes on the same key are paired first
+    /// in, first out, and notes still held when their track ends are cut off
+    /// there. Pitch bend, controllers, and program changes are ignored.
+    #[cfg(feature = "midi")]
+    pub fn from_midi_bytes(bytes: &[u8]) -> Result<Song, UntzError> {
+        use midly::{MetaMessage, MidiMessage, Smf, Timing, TrackEventKind};
+
+        let smf = Smf::parse(bytes).map_err(|err| UntzError::Midi(err.to_string()))?;
+        // Tempo changes apply to every track, whichever one they appear in
+        let mut tempos: Vec<(u64, u32)> = Vec::new();
+        for track in smf.tracks.iter() {
+            let mut tick = 0_u64;
+            for event in track.iter() {
+                tick += event.delta.as_int() as u64;
+                if let TrackEventKind::Meta(MetaMessage::Tempo(tempo)) = event.kind {
+                    tempos.push((tick, tempo.as_int()));
+                }
+            }
+        }
+        tempos.sort_by_key(|&(tick, _)| tick);
+        let seconds = |tick: u64| match smf.header.timing {
+            Timing::Metrical(ticks_per_beat) => {
+                let ticks_per_beat = ticks_per_beat.as_int() as f64;
+                let (mut time, mut last_tick, mut tempo) = (0_f64, 0_u64, MIDI_DEFAULT_TEMPO);
+                for &(change_tick, new_tempo) in tempos.iter().take_while(|&&(change_tick, _)| change_tick < tick) {
+                    time += (change_tick - last_tick) as f64 * tempo as f64 / 1e6 / ticks_per_beat;
+                    last_tick = change_tick;
+                    tempo = new_tempo;
+                }
+                time + (tick - last_tick) as f64 * tempo as f64 / 1e6 / ticks_per_beat
+            },
+            Timing::Timecode(fps, ticks_per_frame) => tick as f64 / (fps.as_f32() as f64 * ticks_per_frame as f64),
+        };
+
+        let mut channel_notes: Vec<Vec<Note>> = (0..16).map(|_| Vec::new()).collect();
+        for track in smf.tracks.iter() {
+            // Start tick and velocity of each held note, by channel and key
+            let mut held: HashMap<(u8, u8), Vec<(u64, u8)>> = HashMap::new();
+            let mut tick = 0_u64;
+            for event in track.iter() {
+                tick += event.delta.as_int() as u64;
+                let (channel, key, vel) = match event.kind {
+                    TrackEventKind::Midi { channel, message: MidiMessage::NoteOn { key, vel } } => (channel.as_int(), key.as_int(), vel.as_int()),
+                    TrackEventKind::Midi { channel, message: MidiMessage::NoteOff { key, .. } } => (channel.as_int(), key.as_int(), 0),
+                    _ => continue,
+                };
+                let starts = held.entry((channel, key)).or_default();
+                if vel > 0 {
+                    starts.push((tick, vel));
+                } else if !starts.is_empty() {
+                    let (start, vel) = starts.remove(0);
+                    let note = midi_note(key, vel, seconds(start), seconds(tick));
+                    channel_notes[channel as usize].push(note);
+                }
+            }
+            for ((channel, key), starts) in held.into_iter() {
+                for (start, vel) in starts {
+                    channel_notes[channel as usize].push(midi_note(key, vel, seconds(start), seconds(tick)));
+                }
+            }
+        }
+
+        let mut song = Song::new();
+        for mut notes in channel_notes.into_iter().filter(|notes| !notes.is_empty()) {
+            notes.sort_by(|a, b| a.start.partial_cmp(&b.start).unwrap());
+            let mut track = Track::new(Instrument::Sine);
+            track.notes = notes;
+            song.track(track);
+        }
+        Ok(song)
+    }
+
+    /// Writes the song as a standard MIDI file. See `to_midi_bytes`.
+    #[cfg(feature = "midi")]
+    pub fn to_midi(&self, path: &str) -> Result<(), UntzError> {
+        let bytes = self.to_midi_bytes()?;
+        let mut file = File::create(path)?;
+        file.write_all(&bytes)?;
+        Ok(())
+    }
+
+    /// Encodes the song as a standard MIDI file at 120 bpm, with a tempo track
+    /// followed by one track per untz track, named with its label, on channels
+    /// in the same order (wrapping after 16). Frequencies go to the nearest key,
+    /// volumes to velocities, and times to the nearest tick. Everything else
+    /// about the sound is lost.
+    #[cfg(feature = "midi")]
+    pub fn to_midi_bytes(&self) -> Result<Vec<u8>, UntzError> {
+        use midly::{Format, Header, MetaMessage, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind};
+
+        let ticks_per_second = MIDI_TICKS_PER_BEAT as f64 * 1e6 / MIDI_DEFAULT_TEMPO as f64;
+        let ticks = |seconds: f64| (seconds * ticks_per_second).round() as u64;
+        // Kept out here since the events only borrow them
+        let labels: Vec<String> = self.tracks.iter().enumerate().map(|(track_idx, track)| track.label(track_idx)).collect();
+        let mut smf = Smf::new(Header::new(Format::Parallel, Timing::Metrical(MIDI_TICKS_PER_BEAT.into())));
+        smf.tracks.push(vec![
+            TrackEvent { delta: 0.into(), kind: TrackEventKind::Meta(MetaMessage::Tempo(MIDI_DEFAULT_TEMPO.into())) },
+            TrackEvent { delta: 0.into(), kind: TrackEventKind::Meta(MetaMessage::EndOfTrack) },
+        ]);
+        for (track_idx, track) in self.tracks.iter().enumerate() {
+            let channel = (track_idx % 16) as u8;
+            // (tick, velocity, key), where a velocity of 0 is a note-off. Sorting
+            // puts note-offs before note-ons at the same tick.
+            let mut events: Vec<(u64, u8, u8)> = Vec::new();
+            for note in track.notes.iter() {
+                let start = track.start_seconds(note.start);
+                let end = start + track.seconds(note.duration);
+                let key = freq_to_midi(note.freq).round().clamp(0.0, 127.0) as u8;
+                let vel = (note.volume * 127.0).round().clamp(1.0, 127.0) as u8;
+                // Notes shorter than a tick would have their note-off sorted first
+                if ticks(end) == ticks(start) {
+                    continue;
+                }
+                events.push((ticks(start), vel, key));
+                events.push((ticks(end), 0, key));
+            }
+            events.sort();
+            let mut midi_track = Vec::with_capacity(events.len() + 2);
+            midi_track.push(TrackEvent { delta: 0.into(), kind: TrackEventKind::Meta(MetaMessage::TrackName(labels[track_idx].as_bytes())) });
+            let mut last_tick = 0_u64;
+            for (tick, vel, key) in events {
+                midi_track.push(TrackEvent {
+                    delta: ((tick - last_tick) as u32).into(),
+                    kind: TrackEventKind::Midi {
+                        channel: channel.into(),
+                        message: MidiMessage::NoteOn { key: key.into(), vel: vel.into() },
+                    },
+                });
+                last_tick = tick;
+            }
+            midi_track.push(TrackEvent { delta: 0.into(), kind: TrackEventKind::Meta(MetaMessage::EndOfTrack) });
+            smf.tracks.push(midi_track);
+        }
+        let mut bytes = Vec::new();
+        smf.write_std(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Plays the song on the default output device, blocking until it has
+    /// finished. The song is rendered at the device's own sample rate, so
+    /// `info.sample_rate` as well as the file options are ignored.
+    #[cfg(feature = "playback")]
+    pub fn play(&self, info: &WriteInfo) -> Result<(), UntzError> {
+        use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+
+        self.validate(info)?;
+        let device = cpal::default_host().default_output_device()
+            .ok_or_else(|| UntzError::Playback(String::from("no output device available")))?;
+        let supported = device.default_output_config().map_err(|err| UntzError::Playback(err.to_string()))?;
+        let sample_format = supported.sample_format();
+        let config: cpal::StreamConfig = supported.into();
+
+        let mut sample_data = self.render_channels(config.sample_rate, info.layout(), None);
+        if let Some(target) = info.normalize {
+            let song_peak = peak(&sample_data);
+            normalize(&mut sample_data, song_peak, target);
+        }
+        // Mono goes to every device channel. Otherwise channels map onto device
+        // channels in order, are folded down for a mono device, and any the
+        // device doesn't have are dropped.
+        let device_channels = config.channels as usize;
+        let mut frames = Vec::with_capacity(sample_data[0].len() * device_channels);
+        for i in 0..sample_data[0].len() {
+            for device_channel in 0..device_channels {
+                let sample = if sample_data.len() == 1 {
+                    sample_data[0][i]
+                } else if device_channels == 1 {
+                    sample_data.iter().map(|channel| channel[i]).sum::<f64>() / sample_data.len() as f64
+                } else {
+                    sample_data.get(device_channel).map_or(0.0, |channel| channel[i])
+                };
+                frames.push(clip(sample, &info.clipping) as f32);
+            }
+        }
+        // A little trailing silence so the device has drained the real audio by
+        // the time the callback reports that it is done
+        frames.resize(frames.len() + config.sample_rate as usize / 10 * device_channels, 0.0);
+
+        let (done_tx, done_rx) = mpsc::channel();
+        let stream = match sample_format {
+            cpal::SampleFormat::F32 => play_stream::<f32>(&device, config, frames, done_tx),
+            cpal::SampleFormat::I16 => play_stream::<i16>(&device, config, frames, done_tx),
+            cpal::SampleFormat::U16 => play_stream::<u16>(&device, config, frames, done_tx),
+            other => Err(UntzError::Playback(format!("unsupported device sample format {:?}", other))),
+        }?;
+        stream.play().map_err(|err| UntzError::Playback(err.to_string()))?;
+        done_rx.recv().unwrap_or(Ok(())).map_err(UntzError::Playback)
+    }
+}
+
+// Microseconds per beat until a MIDI file says otherwise, which is 120 bpm
+#[cfg(feature = "midi")]
+const MIDI_DEFAULT_TEMPO: u32 = 500_000;
+#[cfg(feature = "midi")]
+const MIDI_TICKS_PER_BEAT: u16 = 480;
+
+#[cfg(feature = "midi")]
+fn midi_note(key: u8, vel: u8, start: f64, end: f64) -> Note {
+    Note::from_midi(key, vel as f64 / 127.0, start, end - start)
+}
+
+// Renders a song for writing one chunk at a time, carrying the oversampling
+// filters and DC blocker over from chunk to chunk along with the song's own
+// state. Without a chunk size the whole song comes out as one block.
+struct Renderer<'a> {
+    song: &'a Song,
+    info: &'a WriteInfo,
+    state: SongState,
+    decimator: Option<Decimator>,
+    dc_blocker: Option<DcBlocker>,
+    // In samples at the oversampled rate
+    num_samples: usize,
+    next_sample: usize,
+    block_len: usize,
+    // In frames at the output rate
+    num_frames: usize,
+}
+
+impl<'a> Renderer<'a> {
+    fn new(song: &'a Song, info: &'a WriteInfo, stem: Option<usize>) -> Renderer<'a> {
+        let num_channels = info.channels as usize;
+        let render_rate = info.mix_rate() * info.oversample;
+        let num_samples = song.num_samples(render_rate);
+        Renderer {
+            song,
+            info,
+            state: SongState { stem, ..song.stream_state(info.layout(), render_rate, info.sample_rate) },
+            decimator: if info.oversample > 1 {Some(Decimator::new(num_channels, info.oversample, info.mix_rate()))} else {None},
+            // Like normalizing, DC blocking belongs to the master bus and is left off stems
+            dc_blocker: if info.dc_block && stem.is_none() {Some(DcBlocker::new(num_channels, info.sample_rate))} else {None},
+            num_samples,
+            next_sample: 0,
+            block_len: info.chunk_size.map_or(num_samples, |frames| frames as usize * info.oversample as usize),
+            num_frames: song.num_frames(info),
+        }
+    }
+
+    fn frames_done(&self) -> usize {
+        self.next_sample.div_ceil(self.info.oversample as usize)
+    }
+
+    fn next_block(&mut self, progress: Option<&mut (dyn FnMut(f64) + Send)>) -> Option<Vec<Vec<f64>>> {
+        if self.next_sample >= self.num_samples {
+            return None;
+        }
+        let render_rate = self.info.mix_rate() * self.info.oversample;
+        let len = self.block_len.min(self.num_samples - self.next_sample);
+        let mut block = self.song.render_block(&mut self.state, render_rate, self.info.layout(), self.next_sample, len, progress);
+        self.next_sample += len;
+        if let Some(decimator) = &mut self.decimator {
+            block = decimator.process(&block);
+        }
+        if self.info.mix_rate() != self.info.sample_rate {
+            block = block.iter()
+                .map(|channel| resample(channel, self.info.mix_rate(), self.info.sample_rate, self.info.resampling))
+                .collect();
+        }
+        if let Some(dc_blocker) = &mut self.dc_blocker {
+            dc_blocker.process(&mut block);
+        }
+        Some(block)
+    }
+}
+
+// "dir/song.wav" with "-<suffix>" added before the extension. Path separators
+// and anything else file systems reject in names are replaced, so the stem
+// stays beside the song.
+fn stem_path(filepath: &str, suffix: &str) -> String {
+    let suffix: String = suffix.chars()
+        .map(|c| if c.is_control() || "/\\:*?\"<>|".contains(c) {'_'} else {c})
+        .collect();
+    let path = Path::new(filepath);
+    let stem = path.file_stem().map_or_else(String::new, |stem| stem.to_string_lossy().into_owned());
+    let name = match path.extension() {
+        Some(extension) => format!("{}-{}.{}", stem, suffix, extension.to_string_lossy()),
+        None => format!("{}-{}", stem, suffix),
+    };
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
+
+// Bytes per sample and the WAV format tag for each sample format.
+fn sample_layout(sample_format: &SampleFormat) -> (u16, u16) {
+    match sample_format {
+        SampleFormat::I16 => (2_u16, 1_u16),
+        SampleFormat::I24 => (3_u16, 1_u16),
+        SampleFormat::F32 => (4_u16, WAVE_FORMAT_IEEE_FLOAT),
+    }
+}
+
+fn clip(sample: f64, clipping: &Clipping) -> f64 {
+    match clipping {
+        Clipping::Hard => sample.clamp(-1.0, 1.0),
+        Clipping::Soft => sample.tanh(),
+    }
+}
+
+const DITHER_SEED: u64 = 0x5EED_D17E;
+
+// Quantizes and interleaves the channels into the sample data of a file. `rng`
+// supplies the dither and carries on from one block to the next.
+fn encode_samples(sample_data: &[Vec<f64>], info: &WriteInfo, big_endian: bool, rng: &mut Rng) -> Vec<u8> {
+    let num_samples = sample_data[0].len();
+    let (sample_bytes, _) = sample_layout(&info.sample_format);
+    let mut bytes = Vec::with_capacity(num_samples * sample_data.len() * sample_bytes as usize);
+    for i in 0..num_samples {
+        for channel in sample_data.iter() {
+            let sample = clip(channel[i], &info.clipping);
+            // Triangular noise spanning +/- 1 LSB, from the difference of two uniform draws
+            let dither = if info.dither {rng.next_f64() - rng.next_f64()} else {0.0};
+            match info.sample_format {
+                SampleFormat::I16 => {
+                    let sample_max = 32767_f64;  // 2 ** (2 * 8) / 2 - 1
+                    // Rounded and clamped so +/- 1.0 land on +/- 32767 symmetrically
+                    let val = (sample_max * sample + dither).round().clamp(-sample_max, sample_max) as i16;
+                    bytes.extend_from_slice(&if big_endian {val.to_be_bytes()} else {val.to_le_bytes()});
+                },
+                SampleFormat::I24 => {
+                    let sample_max = 8388607_f64;  // 2 ** (3 * 8) / 2 - 1
+                    let val = (sample_max * sample + dither).round().clamp(-sample_max, sample_max) as i32;
+                    if big_endian {
+                        bytes.extend_from_slice(&val.to_be_bytes()[1..]);
+                    } else {
+                        bytes.extend_from_slice(&val.to_le_bytes()[..3]);
+                    }
+                },
+                SampleFormat::F32 => {
+                    let val = sample as f32;
+                    bytes.extend_from_slice(&if big_endian {val.to_be_bytes()} else {val.to_le_bytes()});
+                },
+            }
+        }
+    }
+    bytes
+}
+
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+// The last 14 bytes of the KSDATAFORMAT_SUBTYPE_* GUIDs
+const WAVE_SUBFORMAT_SUFFIX: [u8; 14] = [0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xAA, 0x00, 0x38, 0x9B, 0x71];
+
+// Speaker positions for the extensible fmt chunk. Mono is front center and
+// stereo front left and right. Beyond that notes are panned along a line of
+// channels rather than around a surround layout, so they get no positions.
+fn wave_channel_mask(num_channels: u16) -> u32 {
+    match num_channels {
+        1 => 0x4,
+        2 => 0x1 | 0x2,
+        _ => 0,
+    }
+}
+
+/// Reads a WAV file. See `read_wav_bytes`.
+pub fn read_wav(path: &str) -> Result<(Vec<f64>, u32, u16), UntzError> {
+    let bytes = std::fs::read(path)?;
+    read_wav_bytes(&bytes)
+}
+
+/// Decodes the contents of a WAV file into interleaved samples, along with the
+/// sample rate and the number of channels. 8, 16, 24 and 32-bit integer PCM
+/// and 32 and 64-bit float are understood, in the plain or the extensible fmt
+/// chunk. 16 to 32-bit integers are scaled the same way `write` scales them,
+/// so reading a written song gives back its samples to within quantization;
+/// 8-bit ones, which `write` never makes, are divided by 128 to stay within
+/// [-1, 1). A data chunk cut short, as by an interrupted write, is read as far
+/// as it goes.
+pub fn read_wav_bytes(bytes: &[u8]) -> Result<(Vec<f64>, u32, u16), UntzError> {
+    let invalid = |msg: String| UntzError::InvalidWav(msg);
+    let u16_at = |data: &[u8], at: usize| u16::from_le_bytes([data[at], data[at + 1]]);
+    let u32_at = |data: &[u8], at: usize| u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]]);
+    if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        return Err(invalid(String::from("not a RIFF WAVE file")));
+    }
+    // Format tag, channels, sample rate and bits per sample, from the fmt chunk
+    let mut format: Option<(u16, u16, u32, u16)> = None;
+    let mut pos = 12;
+    while pos + 8 <= bytes.len() {
+        let id = &bytes[pos..pos + 4];
+        let size = u32_at(bytes, pos + 4) as usize;
+        let body = &bytes[pos + 8..(pos + 8).saturating_add(size).min(bytes.len())];
+        match id {
+            b"fmt " => {
+                if body.len() < 16 {
+                    return Err(invalid(format!("fmt chunk is {} bytes, too short to describe the samples", body.len())));
+                }
+                let mut format_tag = u16_at(body, 0);
+                if format_tag == WAVE_FORMAT_EXTENSIBLE {
+                    if body.len() < 26 {
+                        return Err(invalid(String::from("extensible fmt chunk is missing its subformat")));
+                    }
+                    format_tag = u16_at(body, 24);
+                }
+                format = Some((format_tag, u16_at(body, 2), u32_at(body, 4), u16_at(body, 14)));
+            },
+            b"data" => {
+                let (format_tag, channels, sample_rate, bits) = format.ok_or_else(|| invalid(String::from("data chunk comes before the fmt chunk")))?;
+                if channels == 0 {
+                    return Err(invalid(String::from("no channels")));
+                }
+                if sample_rate == 0 {
+                    return Err(invalid(String::from("sample rate of 0 Hz")));
+                }
+                let decode: fn(&[u8]) -> f64 = match (format_tag, bits) {
+                    (1, 8) => |b| (b[0] as f64 - 128.0) / 128.0,
+                    (1, 16) => |b| i16::from_le_bytes([b[0], b[1]]) as f64 / 32767.0,
+                    (1, 24) => |b| (i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8) as f64 / 8388607.0,
+                    (1, 32) => |b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64 / 2147483647.0,
+                    (WAVE_FORMAT_IEEE_FLOAT, 32) => |b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+                    (WAVE_FORMAT_IEEE_FLOAT, 64) => |b| f64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]),
+                    _ => return Err(invalid(format!("unsupported format {} with {} bits per sample", format_tag, bits))),
+                };
+                // Any partial frame at the end is dropped
+                let frame_bytes = channels as usize * bits as usize / 8;
+                let samples = body[..body.len() - body.len() % frame_bytes]
+                    .chunks_exact(bits as usize / 8)
+                    .map(decode)
+                    .collect();
+                return Ok((samples, sample_rate, channels));
+            },
+            _ => {},
+        }
+        // Chunks are padded to an even length
+        pos = (pos + 8).saturating_add(size).saturating_add(size % 2);
+    }
+    Err(invalid(String::from("no data chunk")))
+}
+
+// A complete smpl chunk with one forward loop from `start` to `end` that
+// repeats until the note is released. The tuning fields say middle C played
+// back at its recorded pitch, and there's no SMPTE offset or sampler data.
+fn smpl_chunk(start: u32, end: u32, sample_rate: u32) -> Vec<u8> {
+    let mut chunk = Vec::with_capacity(68);
+    chunk.extend_from_slice(b"smpl");
+    chunk.extend_from_slice(&(36_u32 + 24).to_le_bytes());
+    let fields = [
+        0,  // manufacturer
+        0,  // product
+        1_000_000_000 / sample_rate,  // sample period in nanoseconds
+        60,  // MIDI unity note
+        0,  // pitch fraction
+        0,  // SMPTE format
+        0,  // SMPTE offset
+        1,  // number of loops
+        0,  // sampler data size
+        // The loop itself
+        0,  // cue point ID
+        0,  // type: forward
+        start,
+        end,
+        0,  // fraction
+        0,  // play count: forever
+    ];
+    for field in fields.iter() {
+        chunk.extend_from_slice(&field.to_le_bytes());
+    }
+    chunk
+}
+
+// Everything in a WAV file up to its sample data, which is `num_frames` long.
+// The sample data and its pad byte follow separately.
+fn wave_header(info: &WriteInfo, num_frames: usize) -> Vec<u8> {
+    // Computing byte sizes
+    let num_channels = info.channels;
+    let (sample_bytes, format_tag) = sample_layout(&info.sample_format);
+    let data_size = (num_frames * num_channels as usize * sample_bytes as usize) as u32;
+    let pad_size = if data_size.is_multiple_of(2) {0_u32} else {1_u32};
+    let info_chunk = info.metadata.info_chunk();
+    let smpl_chunk = info.loop_points.map_or_else(Vec::new, |(start, end)| smpl_chunk(start, end, info.sample_rate));
+    // Players expect the extensible fmt chunk for more than two channels or for
+    // float samples; anything else keeps the plain one, which everything reads.
+    let extensible = num_channels > 2 || format_tag == WAVE_FORMAT_IEEE_FLOAT;
+    let fmt_size: u32 = if extensible {40} else {16};
+    let wave_chunk_size: u32 = 4 + (8 + fmt_size) + smpl_chunk.len() as u32 + info_chunk.len() as u32 + 8 + data_size + pad_size;
+    let header_size = 20 + fmt_size as usize + smpl_chunk.len() + info_chunk.len() + 8;
+    let byte_rate: u32 = info.sample_rate * sample_bytes as u32 * num_channels as u32;
+    let block_align: u16 = sample_bytes * num_channels;
+    let sample_bits: u16 = 8_u16 * sample_bytes;
+
+    let mut file_data = vec![0_u8; header_size];
+    let mut i = 0;
+
+    let mut write_slice = |slice: &[u8]| {
+        merge(&mut file_data[i..i+slice.len()], slice, overwrite);
+        i += slice.len();
+    };
+
+    write_slice(b"RIFF");
+    write_slice(&wave_chunk_size.to_le_bytes());
+    write_slice(b"WAVE");
+
+    write_slice(b"fmt ");
+    write_slice(&fmt_size.to_le_bytes());
+    write_slice(&(if extensible {WAVE_FORMAT_EXTENSIBLE} else {format_tag}).to_le_bytes());
+    write_slice(&num_channels.to_le_bytes());
+    write_slice(&info.sample_rate.to_le_bytes());
+    write_slice(&byte_rate.to_le_bytes());
+    write_slice(&block_align.to_le_bytes());
+    write_slice(&sample_bits.to_le_bytes());
+    if extensible {
+        write_slice(&(22_u16).to_le_bytes());  // cbSize, the bytes that follow
+        write_slice(&sample_bits.to_le_bytes());  // valid bits, all of them
+        write_slice(&wave_channel_mask(num_channels).to_le_bytes());
+        // The subformat GUID is the plain format tag followed by a fixed suffix
+        write_slice(&format_tag.to_le_bytes());
+        write_slice(&WAVE_SUBFORMAT_SUFFIX);
+    }
+    write_slice(&smpl_chunk);
+    write_slice(&info_chunk);
+    write_slice(b"data");
+    write_slice(&data_size.to_le_bytes());
+    debug_assert_eq!(i, header_size);
+
+    file_data
+}
+
+// Streams interleaved frames to the device, sending on `done` once they have
+// all been played or the stream fails.
+#[cfg(feature = "playback")]
+fn play_stream<T>(
+    device: &cpal::Device,
+    config: cpal::StreamConfig,
+    frames: Vec<f32>,
+    done: mpsc::Sender<Result<(), String>>,
+) -> Result<cpal::Stream, UntzError>
+where
+    T: cpal::SizedSample + cpal::FromSample<f32>,
+{
+    use cpal::traits::DeviceTrait;
+
+    let error_done = done.clone();
+    let mut pos = 0;
+    device.build_output_stream(
+        config,
+        move |output: &mut [T], _: &cpal::OutputCallbackInfo| {
+            for out in output.iter_mut() {
+                *out = T::from_sample(frames.get(pos).copied().unwrap_or(0.0));
+                pos += 1;
+            }
+            if pos >= frames.len() {
+                let _ = done.send(Ok(()));
+            }
+        },
+        move |err| {
+            let _ = error_done.send(Err(err.to_string()));
+        },
+        None,
+    ).map_err(|err| UntzError::Playback(err.to_string()))
+}
+
+// AIFF stores its sample rate as an 80-bit IEEE 754 extended float. Sample
+// rates are whole numbers, so the conversion is exact.
+fn extended_float(value: u32) -> [u8; 10] {
+    let mut bytes = [0_u8; 10];
+    if value == 0 {
+        return bytes;
+    }
+    let shift = (value as u64).leading_zeros();
+    let exponent = 16383 + 63 - shift as u16;
+    let mantissa = (value as u64) << shift;
+    bytes[..2].copy_from_slice(&exponent.to_be_bytes());
+    bytes[2..].copy_from_slice(&mantissa.to_be_bytes());
+    bytes
+}
+
+// Everything in an AIFF file up to its sample data, like `wave_header`.
+fn aiff_header(info: &WriteInfo, num_frames: usize) -> Vec<u8> {
+    let num_channels = info.channels;
+    let (sample_bytes, _) = sample_layout(&info.sample_format);
+    let ssnd_size = 8 + (num_frames * num_channels as usize * sample_bytes as usize) as u32;
+    let pad_size = if ssnd_size.is_multiple_of(2) {0_u32} else {1_u32};
+    let form_size: u32 = 4 + (8 + 18) + (8 + ssnd_size + pad_size);
+    let header_size = 54;
+
+    let mut file_data = vec![0_u8; header_size];
+    let mut i = 0;
+
+    let mut write_slice = |slice: &[u8]| {
+        merge(&mut file_data[i..i+slice.len()], slice, overwrite);
+        i += slice.len();
+    };
+
+    write_slice(b"FORM");
+    write_slice(&form_size.to_be_bytes());
+    write_slice(b"AIFF");
+
+    write_slice(b"COMM");
+    write_slice(&(18_u32).to_be_bytes());
+    write_slice(&num_channels.to_be_bytes());
+    write_slice(&(num_frames as u32).to_be_bytes());
+    write_slice(&(8 * sample_bytes).to_be_bytes());
+    write_slice(&extended_float(info.sample_rate));
+
+    write_slice(b"SSND");
+    write_slice(&ssnd_size.to_be_bytes());
+    write_slice(&(0_u32).to_be_bytes());  // offset
+    write_slice(&(0_u32).to_be_bytes());  // block size
+    debug_assert_eq!(i, header_size);
+
+    file_data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::{FRAC_1_SQRT_2, SQRT_2};
+
+    fn song_of(track: Track) -> Song {
+        let mut song = Song::new();
+        song.track(track);
+        song
+    }
+
+    // Amplitude of the `freq` Hz component of `samples`, which should hold a
+    // whole number of its cycles.
+    fn tone_level(samples: &[f64], freq: f64, sample_rate: u32) -> f64 {
+        let (mut re, mut im) = (0.0, 0.0);
+        for (i, sample) in samples.iter().enumerate() {
+            let angle = 2.0 * PI * freq * i as f64 / sample_rate as f64;
+            re += sample * angle.cos();
+            im += sample * angle.sin();
+        }
+        2.0 * f64::hypot(re, im) / samples.len() as f64
+    }
+
+    // Average frequency between the first and last rising zero crossings
+    fn frequency(samples: &[f64], sample_rate: u32) -> f64 {
+        let crossings: Vec<f64> = samples.windows(2).enumerate()
+            .filter(|(_, pair)| pair[0] < 0.0 && pair[1] >= 0.0)
+            .map(|(i, pair)| i as f64 + pair[0] / (pair[0] - pair[1]))
+            .collect();
+        let span = crossings[crossings.len() - 1] - crossings[0];
+        (crossings.len() - 1) as f64 * sample_rate as f64 / span
+    }
+
+    // What writing `song` fails with, having written nothing
+    fn write_error(song: &Song, info: &WriteInfo) -> UntzError {
+        let mut bytes = Vec::new();
+        let err = song.write_to(&mut bytes, info).unwrap_err();
+        assert!(bytes.is_empty());
+        err
+    }
+
+    fn invalid_parameter(song: &Song) -> String {
+        match write_error(song, &WriteInfo::builder().build()) {
+            UntzError::InvalidParameter(msg) => msg,
+            err => panic!("unexpected error: {}", err),
+        }
+    }
+
+    // Power per Hz of `samples` around `freq`, through a narrow band-pass
+    fn band_density(samples: &[f64], freq: f64, sample_rate: u32) -> f64 {
+        let mut filter = Biquad::new(FilterType::BandPass, freq, 4.0, sample_rate);
+        let power = samples.iter().map(|sample| filter.process(*sample).powi(2)).sum::<f64>() / samples.len() as f64;
+        power / freq
+    }
+
+    // Runs `effect` over one block of mono samples
+    fn apply(effect: &Effect, samples: Vec<f64>, sample_rate: u32) -> Vec<f64> {
+        let mut channels = vec![samples];
+        let mut state = effect.state(1, sample_rate, sample_rate);
+        effect.apply_block(&mut state, &mut channels, sample_rate, 0);
+        channels.remove(0)
+    }
+
+    // Writes the song to memory as WAV and reads its samples back, interleaved
+    fn write_and_read(song: &Song, info: &WriteInfo) -> Vec<f64> {
+        let mut bytes = Vec::new();
+        song.write_to(&mut bytes, info).unwrap();
+        read_wav_bytes(&bytes).unwrap().0
+    }
+
+    fn mean(samples: &[f64]) -> f64 {
+        samples.iter().sum::<f64>() / samples.len() as f64
+    }
+
+    #[test]
+    fn triangle_stays_within_volume() {
+        let samples = render_note(&Instrument::Triangle, &Note::new(440.0, 0.5, 0.0, 1.0), 44100);
+        assert_eq!(samples.len(), 44100);
+        assert!(samples.iter().all(|sample| sample.abs() <= 0.5));
+        let max = samples.iter().cloned().fold(f64::MIN, f64::max);
+        let min = samples.iter().cloned().fold(f64::MAX, f64::min);
+        assert!((max - 0.5).abs() < 0.01 && (min + 0.5).abs() < 0.01, "peaks at {} and {}", max, min);
+    }
+
+    #[test]
+    fn release_longer_than_the_note_still_sounds() {
+        let envelope = Envelope { attack: 0.01, decay: 0.1, sustain: 0.7, release: 2.0 };
+        let note = Note::builder().volume(0.5).envelope(envelope).build();
+        let samples = render_note(&Instrument::Sine, &note, 44100);
+        // The 2 s release is half done by the end of the attack, and a quarter
+        // of it is left halfway through the sustain
+        assert!((measure(&samples).peak - 0.5 * 0.495).abs() < 0.001, "peak {}", measure(&samples).peak);
+        let middle = measure(&samples[22050 - 441..22050 + 441]).peak;
+        assert!((middle - 0.5 * 0.7 * 0.25).abs() < 0.002, "{} halfway", middle);
+    }
+
+    #[test]
+    fn note_ending_between_samples_renders() {
+        let mut track = Track::new(Instrument::Sine);
+        track.note(Note::new(440.0, 0.5, 0.1234567, 0.7654321));
+        let song = song_of(track);
+        let samples = song.render(44100);
+        assert_eq!(samples.len(), (song.duration() * 44100.0).ceil() as usize);
+        assert!(song.write_to(&mut Vec::new(), &WriteInfo::builder().build()).is_ok());
+    }
+
+    #[test]
+    fn beat_four_at_120_bpm_starts_at_two_seconds() {
+        let mut track = Track::with_tempo(Instrument::Square, 120.0);
+        track.note(Note::new(440.0, 0.5, 4.0, 1.0));
+        let samples = song_of(track).render(44100);
+        assert_eq!(samples.len(), 110250);
+        assert_eq!(samples.iter().position(|sample| *sample != 0.0), Some(88200));
+    }
+
+    #[test]
+    fn band_limited_saw_aliases_less() {
+        // The 11th harmonic of 3520 Hz, at 38720 Hz, folds back to 5380 Hz
+        let note = Note::new(3520.0, 0.5, 0.0, 1.0);
+        let naive = tone_level(&render_note(&Instrument::Saw, &note, 44100), 5380.0, 44100);
+        let band_limited = tone_level(&render_note(&Instrument::BandLimitedSaw, &note, 44100), 5380.0, 44100);
+        assert!(band_limited < naive / 10.0, "alias at {} against {}", band_limited, naive);
+    }
+
+    #[test]
+    fn glide_sweeps_into_the_next_note() {
+        let mut track = Track::new(Instrument::Sine);
+        track.glide = 0.1;
+        track.arpeggio(&[220.0, 440.0], 0.5, 0.0, 0.5);
+        let samples = song_of(track).render(44100);
+        assert!((frequency(&samples[..22050], 44100) - 220.0).abs() < 0.5);
+        let early = frequency(&samples[22050..24255], 44100);
+        let late = frequency(&samples[24255..26460], 44100);
+        assert!(220.0 < early && early < late && late < 440.0, "{} then {}", early, late);
+        assert!((frequency(&samples[26460..], 44100) - 440.0).abs() < 0.5);
+    }
+
+    #[test]
+    fn glide_from_a_silent_note_starts_on_pitch() {
+        let mut track = Track::new(Instrument::Sine);
+        track.glide = 0.1;
+        track.arpeggio(&[0.0, 440.0], 0.5, 0.0, 0.5);
+        let samples = song_of(track).render(44100);
+        assert!(samples.iter().all(|sample| sample.is_finite()));
+        assert!((frequency(&samples[22050..24255], 44100) - 440.0).abs() < 0.5);
+    }
+
+    #[test]
+    fn rejects_zero_sample_rate() {
+        let song = song_of(Track::metronome(120.0, 1, 4));
+        let err = write_error(&song, &WriteInfo::builder().sample_rate(0).build());
+        assert!(matches!(err, UntzError::InvalidSampleRate(0)));
+    }
+
+    #[test]
+    fn rejects_sample_rate_over_maximum() {
+        let song = song_of(Track::metronome(120.0, 1, 4));
+        let err = write_error(&song, &WriteInfo::builder().sample_rate(MAX_SAMPLE_RATE + 1).build());
+        assert!(matches!(err, UntzError::InvalidSampleRate(rate) if rate == MAX_SAMPLE_RATE + 1));
+    }
+
+    #[test]
+    fn rejects_nan_note_field() {
+        let mut track = Track::new(Instrument::Sine);
+        track.note(Note::new(440.0, 0.5, 0.0, 1.0));
+        track.note(Note::new(f64::NAN, 0.5, 1.0, 1.0));
+        assert_eq!(invalid_parameter(&song_of(track)), "Track 1 note 1 freq is not finite (NaN)");
+    }
+
+    #[test]
+    fn rejects_negative_duration() {
+        let mut track = Track::new(Instrument::Sine);
+        track.name = String::from("lead");
+        track.note(Note::new(440.0, 0.5, 0.0, -1.0));
+        assert_eq!(invalid_parameter(&song_of(track)), "lead note 0 duration is negative (-1)");
+    }
+
+    #[test]
+    fn rejects_bad_effect_parameter() {
+        let mut track = Track::new(Instrument::Sine);
+        track.note(Note::new(440.0, 0.5, 0.0, 1.0));
+        track.effects.push(Effect::Delay { time: 0.25, feedback: 1.5, mix: 0.5 });
+        assert_eq!(invalid_parameter(&song_of(track)), "delay feedback must be in [0, 1) (1.5)");
+    }
+
+    #[test]
+    fn low_pass_cuts_a_saws_highs() {
+        let render = |effects: Vec<Effect>| {
+            let mut track = Track::new(Instrument::Saw);
+            track.note(Note::new(250.0, 0.5, 0.0, 1.0));
+            track.effects = effects;
+            song_of(track).render(44100)
+        };
+        let dry = render(Vec::new());
+        let wet = render(vec![Effect::LowPass { cutoff: 500.0, q: FRAC_1_SQRT_2 }]);
+        assert!(tone_level(&wet, 250.0, 44100) > 0.9 * tone_level(&dry, 250.0, 44100));
+        assert!(tone_level(&wet, 5000.0, 44100) < tone_level(&dry, 5000.0, 44100) / 50.0);
+    }
+
+    #[test]
+    fn fm_without_modulation_is_a_sine() {
+        let note = Note::new(440.0, 0.5, 0.0, 0.5);
+        let fm = render_note(&Instrument::Fm { ratio: 2.0, index: 0.0, index_envelope: None }, &note, 44100);
+        let sine = render_note(&Instrument::Sine, &note, 44100);
+        assert_eq!(fm.len(), sine.len());
+        assert!(fm.iter().zip(sine.iter()).all(|(a, b)| (a - b).abs() < 1e-9));
+    }
+
+    #[test]
+    fn single_harmonic_is_a_sine() {
+        let note = Note::new(440.0, 0.5, 0.0, 0.5);
+        let additive = render_note(&Instrument::Additive(vec![1.0]), &note, 44100);
+        let sine = render_note(&Instrument::Sine, &note, 44100);
+        assert_eq!(additive.len(), sine.len());
+        assert!(additive.iter().zip(sine.iter()).all(|(a, b)| (a - b).abs() < 1e-9));
+    }
+
+    #[cfg(feature = "serde_json")]
+    #[test]
+    fn song_survives_a_json_round_trip() {
+        let index_envelope = Envelope { attack: 0.01, decay: 0.2, sustain: 0.3, release: 0.1 };
+        let mut lead = Track::with_tempo(Instrument::Fm { ratio: 2.0, index: 1.5, index_envelope: Some(index_envelope) }, 96.0);
+        lead.name = String::from("lead");
+        lead.velocity_curve = VelocityCurve::Decibels { range: 24.0 };
+        lead.note(Note::builder().volume(0.6).start(1.0).duration(0.5).pan(-0.3).vibrato(5.0, 20.0).sweep(Sweep::Exponential { end_freq: 660.0 }).build());
+        lead.note(Note::builder().start(1.5).tie(true).pitch_envelope(vec![(0.0, 0.0), (1.0, -2.0)]).build());
+        lead.effects.push(Effect::Filter { filter: FilterType::Peak { gain: -3.0 }, cutoff: 800.0, q: 1.2 });
+        lead.effects.push(Effect::Waveshaper { curve: ShaperCurve::Foldback, drive: 2.0 });
+        let mut drums = Track::new(Instrument::Drum(DrumSound::OpenHiHat));
+        drums.chord(&[100.0, 200.0], 0.8, 0.0, 0.25);
+        drums.filter = Some(NoteFilter { filter: FilterType::HighPass, cutoff: 500.0, peak_cutoff: 2000.0, q: 0.7, envelope: None });
+        drums.volume_automation = vec![(0.0, 1.0), (2.0, 0.5)];
+        let mut song = Song::new();
+        song.track(lead);
+        song.track(drums);
+        song.track(Track::new(Instrument::Noise { color: NoiseColor::Pink }));
+        song.effects.push(Effect::Reverb { room_size: 0.6, mix: 0.2 });
+        song.tail = 1.5;
+        let json = serde_json::to_string(&song).unwrap();
+        let mut back: Song = serde_json::from_str(&json).unwrap();
+        assert!(back == song);
+        back.tracks[1].notes[0].volume = 0.7;
+        assert!(back != song);
+    }
+
+    #[cfg(feature = "serde_json")]
+    #[test]
+    fn deserializing_rejects_an_empty_scale() {
+        assert!(serde_json::from_str::<Scale>(r#"{"cents":[]}"#).is_err());
+        let scale: Scale = serde_json::from_str(r#"{"cents":[0.0,700.0]}"#).unwrap();
+        assert_eq!(scale.freq(1, 100.0), 100.0 * f64::powf(2.0, 700.0 / 1200.0));
+    }
+
+    #[test]
+    fn custom_instruments_are_never_equal() {
+        let custom = Instrument::custom(|_, _| 0.0);
+        assert!(custom != Instrument::custom(|_, _| 0.0));
+        assert!(custom != Instrument::Sine);
+        assert!(Instrument::Sine == Instrument::Sine && Instrument::Sine != Instrument::Square);
+        assert!(Instrument::Pulse { duty: 0.25 } != Instrument::Pulse { duty: 0.5 });
+    }
+
+    #[test]
+    fn odd_data_chunk_is_padded() {
+        let mut track = Track::new(Instrument::Sine);
+        track.note(Note::new(440.0, 0.5, 0.0, 0.01));
+        let info = WriteInfo::builder().sample_format(SampleFormat::I24).build();
+        let mut bytes = Vec::new();
+        song_of(track).write_to(&mut bytes, &info).unwrap();
+        let size_at = |pos: usize| u32::from_le_bytes([bytes[pos], bytes[pos + 1], bytes[pos + 2], bytes[pos + 3]]) as usize;
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(size_at(4), bytes.len() - 8);
+        // Walking the chunks, each padded to an even size, ends exactly at the
+        // end of the file
+        let mut pos = 12;
+        let mut data_size = None;
+        while pos < bytes.len() {
+            let size = size_at(pos + 4);
+            if &bytes[pos..pos + 4] == b"data" {
+                data_size = Some(size);
+            }
+            pos += 8 + size + size % 2;
+        }
+        assert_eq!(pos, bytes.len());
+        assert_eq!(data_size, Some(441 * 3));
+        assert_eq!(read_wav_bytes(&bytes).unwrap().0.len(), 441);
+    }
+
+    #[test]
+    fn noise_colors_roll_off() {
+        for (color, slope) in [(NoiseColor::White, 0.0), (NoiseColor::Pink, -3.0), (NoiseColor::Brown, -6.0)] {
+            let instrument = Instrument::Noise { color };
+            let note = Note::new(440.0, 0.5, 0.0, 4.0);
+            let samples = render_note(&instrument, &note, 44100);
+            assert_eq!(samples, render_note(&instrument, &note, 44100));
+            // dB per octave over the three octaves from 500 Hz to 4 kHz
+            let measured = 10.0 * (band_density(&samples, 4000.0, 44100) / band_density(&samples, 500.0, 44100)).log10() / 3.0;
+            assert!((measured - slope).abs() < 1.0, "{} dB per octave instead of {}", measured, slope);
+        }
+    }
+
+    #[test]
+    fn bitcrush_steps_a_sine() {
+        let mut track = Track::new(Instrument::Sine);
+        track.note(Note::new(440.0, 1.0, 0.0, 0.5));
+        track.effects.push(Effect::Bitcrush { bit_depth: 4, downsample: 4 });
+        let samples = song_of(track).render(44100);
+        // 4 bits leave 16 steps of 1/8, each held for 4 samples
+        let mut steps: Vec<i64> = samples.iter().map(|sample| (sample * 8.0) as i64).collect();
+        assert!(samples.iter().all(|sample| (sample * 8.0).fract() == 0.0));
+        assert!(samples.chunks(4).all(|chunk| chunk.iter().all(|sample| *sample == chunk[0])));
+        steps.sort_unstable();
+        steps.dedup();
+        assert_eq!(steps, (-8..8).collect::<Vec<i64>>());
+    }
+
+    #[test]
+    fn ring_mod_moves_a_tone_to_sidebands() {
+        let mut track = Track::new(Instrument::Sine);
+        track.note(Note::new(440.0, 0.8, 0.0, 1.0));
+        track.effects.push(Effect::RingMod { freq: 100.0 });
+        let samples = song_of(track).render(44100);
+        assert!((tone_level(&samples, 340.0, 44100) - 0.4).abs() < 0.01);
+        assert!((tone_level(&samples, 540.0, 44100) - 0.4).abs() < 0.01);
+        assert!(tone_level(&samples, 440.0, 44100) < 0.01);
+    }
+
+    #[test]
+    fn reverb_turns_an_impulse_into_decaying_reflections() {
+        let mut impulse = vec![0_f64; 44100];
+        impulse[0] = 1.0;
+        let response = apply(&Effect::Reverb { room_size: 0.5, mix: 1.0 }, impulse, 44100);
+        // Nothing comes back before the shortest comb's delay of 0.0297 s
+        assert!(response[..1310].iter().all(|sample| *sample == 0.0));
+        assert!(response[1310] != 0.0);
+        let energy: Vec<f64> = response[4410..].chunks(4410).map(|window| measure(window).rms).collect();
+        assert!(energy.windows(2).all(|pair| pair[1] < pair[0]), "{:?}", energy);
+        assert!(energy[energy.len() - 1] > 0.0);
+    }
+
+    #[test]
+    fn dc_blocker_removes_an_offset() {
+        let mut track = Track::new(Instrument::custom(|_, _| 1.0));
+        track.note(Note::new(440.0, 0.5, 0.0, 2.0));
+        let song = song_of(track);
+        let info = |dc_block| WriteInfo::builder().sample_format(SampleFormat::F32).dc_block(dc_block).build();
+        let plain = write_and_read(&song, &info(false));
+        let blocked = write_and_read(&song, &info(true));
+        assert!((mean(&plain[44100..]) - 0.5).abs() < 1e-6);
+        assert!(mean(&blocked[..44100]) > mean(&blocked[44100..]).abs());
+        assert!(mean(&blocked[44100..]).abs() < 0.01);
+    }
+
+    #[test]
+    fn dither_cuts_distortion_on_a_quiet_sine() {
+        // A 1 kHz sine 1.5 LSBs high quantizes to something close to a square
+        let mut track = Track::new(Instrument::Sine);
+        track.note(Note::new(1000.0, 1.5 / 32767.0, 0.0, 1.0));
+        let song = song_of(track);
+        let third_harmonic = |dither| {
+            let samples = write_and_read(&song, &WriteInfo::builder().dither(dither).build());
+            tone_level(&samples, 3000.0, 44100) / tone_level(&samples, 1000.0, 44100)
+        };
+        let (plain, dithered) = (third_harmonic(false), third_harmonic(true));
+        assert!(dithered < plain / 4.0, "{} with dither against {}", dithered, plain);
+    }
+
+    #[test]
+    fn full_scale_quantizes_symmetrically() {
+        let mut track = Track::new(Instrument::custom(|t, _| if t < 0.5 {1.0} else {-1.0}));
+        track.note(Note::new(440.0, 1.0, 0.0, 1.0));
+        let mut bytes = Vec::new();
+        song_of(track).write_to(&mut bytes, &WriteInfo::builder().format(Format::RawPcm).build()).unwrap();
+        let samples: Vec<i16> = bytes.chunks(2).map(|pair| i16::from_le_bytes([pair[0], pair[1]])).collect();
+        assert_eq!(samples.iter().max(), Some(&32767));
+        assert_eq!(samples.iter().min(), Some(&-32767));
+    }
+
+    #[test]
+    fn empty_song_writes_a_silent_file() {
+        let mut bytes = Vec::new();
+        Song::new().write_to(&mut bytes, &WriteInfo::builder().build()).unwrap();
+        let (samples, sample_rate, channels) = read_wav_bytes(&bytes).unwrap();
+        assert!(samples.is_empty());
+        assert_eq!((sample_rate, channels), (44100, 1));
+    }
+
+    #[test]
+    fn zero_length_note_is_skipped() {
+        let mut track = Track::new(Instrument::Sine);
+        track.note(Note::new(440.0, 0.5, 1.0, 0.0));
+        let samples = song_of(track).render(44100);
+        assert_eq!(samples.len(), 44100);
+        assert!(samples.iter().all(|sample| *sample == 0.0));
+    }
+
+    #[test]
+    fn compressor_reduces_steady_level_by_ratio() {
+        // Full scale is 20 dB over the threshold, so 4:1 leaves it 5 dB over
+        let mut track = Track::new(Instrument::custom(|_, _| 1.0));
+        track.note(Note::new(440.0, 1.0, 0.0, 1.0));
+        let mut song = song_of(track);
+        song.effects.push(Effect::Compressor { threshold: -20.0, ratio: 4.0, attack: 0.001, release: 0.1 });
+        let samples = song.render(44100);
+        let level = 20.0 * samples[samples.len() - 1].log10();
+        assert!((level + 15.0).abs() < 0.01, "{} dBFS", level);
+    }
+
+    #[test]
+    fn chorus_delay_follows_its_lfo() {
+        let (rate, depth) = (2.0, 0.005);
+        // Through a ramp, the wet signal falls behind by exactly the delay
+        let ramp = (0..44100).map(|i| i as f64).collect();
+        let wet = apply(&Effect::Chorus { rate, depth, mix: 1.0 }, ramp, 44100);
+        let delay = |i: usize| (i as f64 - wet[i]) / 44100.0;
+        let delays: Vec<f64> = (1000..44100).map(delay).collect();
+        let shortest = delays.iter().cloned().fold(f64::MAX, f64::min);
+        let longest = delays.iter().cloned().fold(f64::MIN, f64::max);
+        assert!((shortest - CHORUS_BASE_DELAY).abs() < 1e-6);
+        assert!((longest - CHORUS_BASE_DELAY - depth).abs() < 1e-6);
+        // and is back where it was one LFO cycle later
+        assert!((1000..22050).step_by(1000).all(|i| (delay(i) - delay(i + 22050)).abs() < 1e-9));
+    }
+
+    #[test]
+    fn measure_finds_a_sines_peak_and_rms() {
+        let samples = render_note(&Instrument::Sine, &Note::new(441.0, 0.8, 0.0, 1.0), 44100);
+        let levels = measure(&samples);
+        assert!((levels.peak - 0.8).abs() < 1e-9);
+        assert!((levels.rms - 0.8 / SQRT_2).abs() < 1e-6);
+        assert!((levels.peak_dbfs() - 20.0 * f64::log10(0.8)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn overdriven_mix_reports_clipping() {
+        let mut song = Song::new();
+        for _ in 0..2 {
+            let mut track = Track::new(Instrument::Sine);
+            track.note(Note::new(441.0, 0.8, 0.0, 1.0));
+            song.track(track);
+        }
+        let report = song.write_to(&mut Vec::new(), &WriteInfo::builder().build()).unwrap();
+        assert!(report.clipped_samples > 0);
+        assert!((report.max_overshoot - 0.6).abs() < 1e-9);
+        song.master = 0.5;
+        let report = song.write_to(&mut Vec::new(), &WriteInfo::builder().build()).unwrap();
+        assert_eq!(report, ClipReport { clipped_samples: 0, max_overshoot: 0.0 });
+    }
+
+    #[test]
+    fn oversampling_reduces_aliasing_from_distortion() {
+        // Hard clipping a 3 kHz sine adds odd harmonics, and the 15th, at 45 kHz,
+        // folds back to 900 Hz. The master is turned down so the decimation
+        // filter's ripple doesn't clip again at the output rate.
+        let mut track = Track::new(Instrument::Sine);
+        track.note(Note::new(3000.0, 0.5, 0.0, 1.0));
+        let mut song = song_of(track);
+        song.effects.push(Effect::Waveshaper { curve: ShaperCurve::HardClip, drive: 8.0 });
+        song.master = 0.5;
+        let alias = |factor| {
+            let samples = write_and_read(&song, &WriteInfo::builder().sample_format(SampleFormat::F32).oversample(factor).build());
+            tone_level(&samples, 900.0, 44100)
+        };
+        let (plain, oversampled) = (alias(1), alias(4));
+        assert!(oversampled < plain / 10.0, "alias at {} against {}", oversampled, plain);
+    }
+
+    #[test]
+    fn pitch_envelope_bends_up_an_octave() {
+        let note = Note::builder().freq(220.0).volume(0.5).pitch_envelope(vec![(0.0, 0.0), (1.0, 12.0)]).build();
+        let samples = render_note(&Instrument::Sine, &note, 44100);
+        assert!((frequency(&samples[..441], 44100) / 220.0 - 1.0).abs() < 0.01);
+        assert!((frequency(&samples[44100 - 441..], 44100) / 440.0 - 1.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn flanger_notches_sweep_through_a_tone() {
+        // Delaying a 1 kHz sine by the flanger's 1 to 3 ms cancels it at 1.5 ms
+        // and reinforces it at 1 and 2 ms
+        let mut track = Track::new(Instrument::Sine);
+        track.note(Note::new(1000.0, 0.4, 0.0, 2.0));
+        track.effects.push(Effect::Flanger { rate: 0.5, depth: 0.002, feedback: 0.0, mix: 0.5 });
+        let samples = song_of(track).render(44100);
+        let peaks: Vec<f64> = samples[4410..].chunks(441).map(|window| measure(window).peak).collect();
+        let quietest = peaks.iter().cloned().fold(f64::MAX, f64::min);
+        let loudest = peaks.iter().cloned().fold(f64::MIN, f64::max);
+        assert!(quietest < 0.1 * loudest, "{} against {}", quietest, loudest);
+    }
+
+    #[test]
+    fn more_drive_adds_harmonics() {
+        let third_harmonic = |drive| {
+            let mut track = Track::new(Instrument::Sine);
+            track.note(Note::new(441.0, 0.5, 0.0, 1.0));
+            track.effects.push(Effect::Waveshaper { curve: ShaperCurve::Tanh, drive });
+            let samples = song_of(track).render(44100);
+            tone_level(&samples, 1323.0, 44100) / tone_level(&samples, 441.0, 44100)
+        };
+        assert!(third_harmonic(4.0) > 5.0 * third_harmonic(1.0));
+        // Foldback reflects off full scale rather than sticking to it
+        assert!((ShaperCurve::Foldback.shape(1.5) - 0.5).abs() < 1e-12);
+        assert!((ShaperCurve::Foldback.shape(-1.25) + 0.75).abs() < 1e-12);
+    }
+
+    #[test]
+    fn comb_and_allpass_impulse_responses() {
+        let impulse = |n: usize| if n == 0 {1.0} else {0.0};
+        let mut comb = CombFilter::new(5, 0.5);
+        for n in 0..21 {
+            let expected = if n > 0 && n % 5 == 0 {f64::powi(0.5, n as i32 / 5 - 1)} else {0.0};
+            assert!((comb.process(impulse(n)) - expected).abs() < 1e-12, "comb at {}", n);
+        }
+        let mut allpass = AllpassFilter::new(5, 0.7);
+        for n in 0..21 {
+            let expected = match n {
+                0 => -0.7,
+                n if n % 5 == 0 => (1.0 - 0.49) * f64::powi(0.7, n as i32 / 5 - 1),
+                _ => 0.0,
+            };
+            assert!((allpass.process(impulse(n)) - expected).abs() < 1e-12, "allpass at {}", n);
+        }
+    }
+
+    #[test]
+    fn drums_decay_instead_of_sustaining() {
+        for sound in [DrumSound::Kick, DrumSound::Snare, DrumSound::ClosedHiHat, DrumSound::OpenHiHat] {
+            let samples = render_note(&Instrument::Drum(sound), &Note::new(60.0, 1.0, 0.0, 2.0), 44100);
+            let hit = measure(&samples[..2205]).rms;
+            let tail = measure(&samples[44100..]).rms;
+            assert!(hit > 0.05 && tail < hit / 20.0, "{} then {}", hit, tail);
+        }
+    }
+
+    #[test]
+    fn resampling_keeps_a_sines_frequency() {
+        let samples = render_note(&Instrument::Sine, &Note::new(1000.0, 0.5, 0.0, 1.0), 48000);
+        for quality in [Resampling::Linear, Resampling::Sinc] {
+            let resampled = resample(&samples, 48000, 44100, quality);
+            assert_eq!(resampled.len(), 44100);
+            assert!((frequency(&resampled, 44100) - 1000.0).abs() < 0.1);
+        }
+        // Above the new Nyquist, 23 kHz is filtered out instead of folding back
+        // to 21.1 kHz
+        let high = render_note(&Instrument::Sine, &Note::new(23000.0, 0.5, 0.0, 1.0), 48000);
+        let level = |quality| tone_level(&resample(&high, 48000, 44100, quality), 21100.0, 44100);
+        assert!(level(Resampling::Sinc) < 0.01 && level(Resampling::Sinc) < level(Resampling::Linear) / 10.0);
+    }
+
+    #[test]
+    fn crossfade_keeps_overlapping_notes_level() {
+        // RMS over each 50 ms of the overlap, relative to one note on its own
+        let overlap = |crossfade| {
+            let mut track = Track::new(Instrument::Sine);
+            track.crossfade = crossfade;
+            track.note(Note::new(440.0, 0.5, 0.0, 1.0));
+            track.note(Note::new(660.0, 0.5, 0.5, 1.0));
+            let samples = song_of(track).render(44100);
+            samples[22050..44100].chunks(2205).map(|window| measure(window).rms / (0.5 / SQRT_2)).collect::<Vec<f64>>()
+        };
+        assert!(overlap(true).iter().all(|level| (level - 1.0).abs() < 0.1), "{:?}", overlap(true));
+        assert!(overlap(false).iter().all(|level| *level > 1.3));
+    }
+
+    #[test]
+    fn filter_types_respond_as_expected_at_the_cutoff() {
+        let render = |effect| {
+            let mut track = Track::new(Instrument::Sine);
+            track.note(Note::new(1000.0, 0.4, 0.0, 1.0));
+            track.effects.push(effect);
+            song_of(track).render(44100)
+        };
+        let responses = [
+            (FilterType::LowPass, FRAC_1_SQRT_2),
+            (FilterType::HighPass, FRAC_1_SQRT_2),
+            (FilterType::BandPass, 1.0),
+            (FilterType::Notch, 0.0),
+            (FilterType::Peak { gain: 6.0 }, f64::powf(10.0, 6.0 / 20.0)),
+        ];
+        for (filter, expected) in responses {
+            let samples = render(Effect::Filter { filter, cutoff: 1000.0, q: FRAC_1_SQRT_2 });
+            let gain = tone_level(&samples[22050..], 1000.0, 44100) / 0.4;
+            assert!((gain - expected).abs() < 0.01, "gain {} instead of {}", gain, expected);
+        }
+        // and the LowPass shorthand is the same filter
+        assert_eq!(
+            render(Effect::LowPass { cutoff: 1000.0, q: FRAC_1_SQRT_2 }),
+            render(Effect::Filter { filter: FilterType::LowPass, cutoff: 1000.0, q: FRAC_1_SQRT_2 }),
+        );
+    }
+
+    #[test]
+    fn filter_envelope_opens_up_over_the_note() {
+        let mut track = Track::new(Instrument::Saw);
+        track.filter = Some(NoteFilter {
+            filter: FilterType::LowPass,
+            cutoff: 200.0,
+            peak_cutoff: 8000.0,
+            q: FRAC_1_SQRT_2,
+            envelope: Some(Envelope { attack: 1.0, decay: 0.0, sustain: 1.0, release: 0.0 }),
+        });
+        track.note(Note::new(110.0, 0.5, 0.0, 1.0));
+        let samples = song_of(track).render(44100);
+        // The 40th harmonic in each tenth of a second, skipping the first, which
+        // has the edge the saw starts on in it
+        let highs: Vec<f64> = samples.chunks(4410).skip(1).map(|window| tone_level(window, 4400.0, 44100)).collect();
+        assert!(highs.windows(2).all(|pair| pair[1] > pair[0]), "{:?}", highs);
+        assert!(highs[highs.len() - 1] > 10.0 * highs[0]);
+    }
+
+    #[test]
+    fn stems_are_one_file_per_track_of_equal_length() {
+        let dir = std::env::temp_dir().join(format!("untz-stems-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut song = Song::new();
+        for (name, start, duration) in [("bass", 0.0, 1.0), ("lead", 0.5, 1.5)] {
+            let mut track = Track::new(Instrument::Sine);
+            track.name = String::from(name);
+            track.note(Note::new(440.0, 0.5, start, duration));
+            song.track(track);
+        }
+        let info = WriteInfo::builder().filepath(dir.join("song.wav").to_str().unwrap()).build();
+        let reports = song.write_stems(&info);
+        let lengths: Vec<usize> = ["song-bass.wav", "song-lead.wav"].iter()
+            .map(|name| read_wav(dir.join(name).to_str().unwrap()).unwrap().0.len())
+            .collect();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(reports.unwrap().len(), 2);
+        assert_eq!(lengths, vec![88200, 88200]);
+    }
+
+    #[test]
+    fn sweep_passes_the_midpoint_frequency() {
+        for (sweep, midpoint) in [(Sweep::Linear { end_freq: 800.0 }, 500.0), (Sweep::Exponential { end_freq: 800.0 }, 400.0)] {
+            let note = Note::builder().freq(200.0).volume(0.5).sweep(sweep).build();
+            let samples = render_note(&Instrument::Sine, &note, 44100);
+            let measured = frequency(&samples[22050 - 441..22050 + 441], 44100);
+            assert!((measured / midpoint - 1.0).abs() < 0.01, "{} Hz instead of {}", measured, midpoint);
+        }
+        // Sweeping to where it started is just a plain note
+        let plain = render_note(&Instrument::Sine, &Note::builder().freq(200.0).build(), 44100);
+        for sweep in [Sweep::Linear { end_freq: 200.0 }, Sweep::Exponential { end_freq: 200.0 }] {
+            assert_eq!(render_note(&Instrument::Sine, &Note::builder().freq(200.0).sweep(sweep).build(), 44100), plain);
+        }
+    }
+
+    #[test]
+    fn zero_width_folds_stereo_to_mono() {
+        let mut track = Track::new(Instrument::Sine);
+        track.note(Note::builder().freq(440.0).volume(0.4).duration(0.5).pan(-1.0).build());
+        track.note(Note::builder().freq(660.0).volume(0.4).duration(0.5).pan(0.8).build());
+        let mut song = song_of(track);
+        let info = WriteInfo::builder().stereo(true).sample_format(SampleFormat::F32).build();
+        assert!(write_and_read(&song, &info).chunks(2).any(|frame| frame[0] != frame[1]));
+        song.effects.push(Effect::Width { width: 0.0 });
+        let samples = write_and_read(&song, &info);
+        assert!(samples.iter().any(|sample| *sample != 0.0));
+        assert!(samples.chunks(2).all(|frame| frame[0] == frame[1]));
+    }
+
+    #[test]
+    fn tied_notes_attack_once() {
+        let attacks = |tie| {
+            let envelope = Envelope { attack: 0.05, decay: 0.0, sustain: 1.0, release: 0.05 };
+            let mut track = Track::new(Instrument::Sine);
+            track.note(Note::builder().volume(0.5).duration(0.5).envelope(envelope.clone()).build());
+            track.note(Note::builder().volume(0.5).start(0.5).duration(0.5).envelope(envelope).tie(tie).build());
+            let samples = song_of(track).render(44100);
+            // Times the level in each 10 ms rises back past half the volume
+            let peaks: Vec<f64> = samples.chunks(441).map(|window| measure(window).peak).collect();
+            peaks.windows(2).filter(|pair| pair[0] < 0.25 && pair[1] >= 0.25).count()
+        };
+        assert_eq!(attacks(false), 2);
+        assert_eq!(attacks(true), 1);
+    }
+
+    #[test]
+    fn major_run_climbs_to_the_octave() {
+        let run = Scale::mode(Mode::Major).run(note_freq("C4").unwrap(), 8);
+        let expected: Vec<f64> = ["C4", "D4", "E4", "F4", "G4", "A4", "B4", "C5"].iter().map(|name| note_freq(name).unwrap()).collect();
+        assert_eq!(run.len(), expected.len());
+        assert!(run.iter().zip(expected.iter()).all(|(freq, expected)| (freq - expected).abs() < 1e-9));
+    }
+}
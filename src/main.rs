@@ -1,206 +1,112 @@
-use std::fs::File;
-use std::io;
-use std::io::prelude::*;
-use std::f64::consts::PI;
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
 
-enum Instrument {
-    Sine,
-    Square,
-    Saw,
-}
+use clap::{Parser, ValueEnum};
 
-struct Note {
-    freq: f64,
-    volume: f64,
-    start: f64,
-    duration: f64,
-}
+use untz::{Format, SampleFormat, Song, WriteInfo};
 
-struct Track {
-    notes: Vec<Note>,
-    instrument: Instrument,
+#[derive(Clone, Copy, ValueEnum)]
+enum FormatArg {
+    Wave,
+    Aiff,
+    Raw,
 }
 
-struct Song {
-    tracks: Vec<Track>,
+#[derive(Clone, Copy, ValueEnum)]
+enum SampleFormatArg {
+    I16,
+    I24,
+    F32,
 }
 
-enum Format {
-    Wave,
-}
-
-struct WriteInfo {
-    filepath: String,
+/// Render a serialized song to an audio file.
+#[derive(Parser)]
+#[command(version)]
+struct Args {
+    /// Song to render, as JSON or TOML (picked by the file extension), or a
+    /// MIDI file when built with the midi feature
+    input: PathBuf,
+    /// Audio file to write
+    output: PathBuf,
+    /// Output sample rate in Hz
+    #[arg(short = 'r', long, default_value_t = 44100)]
     sample_rate: u32,
+    /// Number of output channels, with notes panned across them
+    #[arg(short, long, default_value_t = 1)]
+    channels: u16,
+    /// Shorthand for two channels
+    #[arg(short, long, conflicts_with = "channels")]
     stereo: bool,
-    format: Format,
-}
-
-fn overwrite<T>(_curr: T, new: T) -> T {
-    new
-}
-
-fn add<T: std::ops::Add<Output = T>>(curr: T, new: T) -> T {
-    curr + new
-}
-
-fn grab<'a, T>(vec: &'a mut Vec<T>, offset: &mut usize, len: usize) -> &'a mut [T] {
-    let start: usize = *offset;
-    *offset += len;
-    return &mut vec[start..*offset];
+    #[arg(short, long, value_enum, default_value_t = FormatArg::Wave)]
+    format: FormatArg,
+    #[arg(long, value_enum, default_value_t = SampleFormatArg::I16)]
+    sample_format: SampleFormatArg,
+    /// Render at this multiple of the sample rate to reduce aliasing
+    #[arg(long, default_value_t = 1)]
+    oversample: u32,
+    /// Mix at this sample rate and resample to the output rate when writing
+    #[arg(long)]
+    render_rate: Option<u32>,
+    /// Render and write this many frames at a time, so long songs don't have
+    /// to fit in memory
+    #[arg(long)]
+    chunk_size: Option<u32>,
+    /// First and last frame of a sustain loop to store in the WAV file
+    #[arg(long = "loop", num_args = 2, value_names = ["START", "END"])]
+    loop_points: Option<Vec<u32>>,
+    /// Write each track to its own file, named after OUTPUT with the track's
+    /// name (or "Track N") added, instead of the mix
+    #[arg(long)]
+    stems: bool,
 }
 
-fn merge<T: Copy>(dst: &mut [T], src: &[T], merge_fn: fn(T, T) -> T) {
-    if dst.len() != src.len() {
-        panic!("Mismatched length!");
-    }
-    for i in 0..dst.len() {
-        dst[i] = merge_fn(dst[i], src[i]);
+fn load_song(path: &PathBuf) -> Result<Song, Box<dyn Error>> {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        #[cfg(feature = "midi")]
+        Some("mid") | Some("midi") => Ok(Song::from_midi_bytes(&fs::read(path)?)?),
+        Some("toml") => Ok(toml::from_str(&fs::read_to_string(path)?)?),
+        _ => Ok(serde_json::from_str(&fs::read_to_string(path)?)?),
     }
 }
 
-impl Track {
-    fn new(instrument: Instrument) -> Track {
-        Track {
-            instrument: instrument,
-            notes: Vec::new(),
-        }
+fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::parse();
+    let song = load_song(&args.input)?;
+    let mut builder = WriteInfo::builder()
+        .filepath(&args.output.to_string_lossy())
+        .sample_rate(args.sample_rate)
+        .channels(if args.stereo {2} else {args.channels})
+        .format(match args.format {
+            FormatArg::Wave => Format::Wave,
+            FormatArg::Aiff => Format::Aiff,
+            FormatArg::Raw => Format::RawPcm,
+        })
+        .sample_format(match args.sample_format {
+            SampleFormatArg::I16 => SampleFormat::I16,
+            SampleFormatArg::I24 => SampleFormat::I24,
+            SampleFormatArg::F32 => SampleFormat::F32,
+        })
+        .oversample(args.oversample);
+    if let Some(rate) = args.render_rate {
+        builder = builder.render_rate(rate);
     }
-
-    fn note(&mut self, note: Note) {
-        self.notes.push(note);
+    if let Some(frames) = args.chunk_size {
+        builder = builder.chunk_size(frames);
     }
-}
-
-impl Song {
-    fn new() -> Song {
-        Song {
-            tracks: Vec::new(),
-        }
+    if let Some(points) = args.loop_points {
+        builder = builder.loop_points(points[0], points[1]);
     }
-
-    fn track(&mut self, track: Track) {
-        self.tracks.push(track);
-    }
-
-    fn write(&self, info: &WriteInfo) -> Result<(), io::Error> {
-        let mut file = File::create(&info.filepath)?;
-        let mut total_length = 0_f64;
-        for track in self.tracks.iter() {
-            for note in track.notes.iter() {
-                let end_time = note.start + note.duration;
-                if end_time > total_length {
-                    total_length = end_time;
-                }
-            }
-        }
-        // Computing byte sizes
-        let num_samples = (total_length * info.sample_rate as f64).ceil() as u32;
-        let num_channels = if info.stereo {2_u16} else {1_u16};
-        let sample_bytes = 2_u16;
-        let data_size = num_samples * num_channels as u32 * sample_bytes as u32;
-        let pad_size = if data_size % 2 == 0 {0_u32} else {1_u32};
-        let wave_chunk_size: u32 = 36 + data_size + pad_size;
-        let file_size = (wave_chunk_size + 8) as usize;
-        let byte_rate: u32 = info.sample_rate * sample_bytes as u32 * num_channels as u32;
-        let block_align: u16 = sample_bytes * num_channels;
-        let sample_bits: u16 = 8_u16 * sample_bytes;
-
-        let mut file_data = vec![0_u8; file_size];
-        let mut i = 0;
-
-        let mut write_slice = |slice: &[u8]| {
-            merge(&mut file_data[i..i+slice.len()], slice, overwrite);
-            i += slice.len();
-        };
-
-        write_slice(b"RIFF");
-        write_slice(&wave_chunk_size.to_le_bytes());
-        write_slice(b"WAVE");
-
-        write_slice(b"fmt ");
-        write_slice(&(16_u32).to_le_bytes());
-        write_slice(b"\x01\x00");
-        write_slice(&num_channels.to_le_bytes());
-        write_slice(&info.sample_rate.to_le_bytes());
-        write_slice(&byte_rate.to_le_bytes());
-        write_slice(&block_align.to_le_bytes());
-        write_slice(&sample_bits.to_le_bytes());
-        write_slice(b"data");
-        write_slice(&data_size.to_le_bytes());
-        // Add sample data
-
-        let mut sample_data = vec![0_f64; num_samples as usize];
-        for track in self.tracks.iter() {
-            for note in track.notes.iter() {
-                let mut note_samples = vec![0_f64; (note.duration * info.sample_rate as f64) as usize];
-                for item in note_samples.iter_mut().enumerate() {
-                    let t = item.0 as f64 / info.sample_rate as f64;
-                    *item.1 = note.volume * match &track.instrument {
-                        Instrument::Sine => {
-                            f64::sin(t * 2.0 * PI * note.freq)
-                        },
-                        Instrument::Square => {
-                            if f64::floor(t * 2.0 * note.freq) as u32 % 2 == 0 {1.0} else {-1.0}
-                        },
-                        Instrument::Saw => {
-                            2.0 * f64::fract(t * note.freq) - 1.0
-                        },
-                    };
-                }
-                let start_idx = (note.start * info.sample_rate as f64) as usize;
-                merge(&mut sample_data[start_idx..start_idx+note_samples.len()], &note_samples, add);
-            }
+    let info = builder.build();
+    let reports = if args.stems {song.write_stems(&info)?} else {vec![song.write(&info)?]};
+    for report in reports {
+        if report.clipped_samples > 0 {
+            eprintln!(
+                "warning: {} samples clipped (peak {:.2} dB over full scale)",
+                report.clipped_samples,
+                20.0 * (1.0 + report.max_overshoot).log10(),
+            );
         }
-
-        let sample_max = 32767_f64;  // 2 ** (2 * 8) / 2 - 1
-        for sample in sample_data {
-            let val = (sample_max * sample.clamp(-1.0, 1.0)).floor() as i16;
-            let val_bytes = val.to_le_bytes();
-            for _ in 0..num_channels {
-                write_slice(&val_bytes);
-            }
-        }
-
-        file.write(&file_data);
-
-        Ok(())
     }
-}
-
-fn main() {
-    let mut track1 = Track::new(Instrument::Sine);
-    track1.note(Note {
-        freq: 440.0,
-        duration: 1.0,
-        start: 0.0,
-        volume: 0.8,
-    });
-    let mut track2 = Track::new(Instrument::Square);
-    track2.note(Note {
-        freq: 440.0,
-        duration: 1.0,
-        start: 0.0,
-        volume: 0.2,
-    });
-    let mut track3 = Track::new(Instrument::Saw);
-    track3.note(Note {
-        freq: 440.0,
-        duration: 1.0,
-        start: 0.0,
-        volume: 0.3,
-    });
-
-    let mut song = Song::new();
-    song.track(track1);
-    //song.track(track2);
-    //song.track(track3);
-    song.write(
-        &WriteInfo {
-            filepath: String::from("test.wav"),
-            sample_rate: 44100,
-            stereo: false,
-            format: Format::Wave,
-        }
-    );
+    Ok(())
 }